@@ -0,0 +1,240 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+use tokio::net::lookup_host;
+use tracing::warn;
+
+/// A pattern in the upstream allowlist
+#[derive(Debug, Clone)]
+pub enum UpstreamPattern {
+    /// An IP network in CIDR notation, e.g. `10.0.0.0/8`, or a single IP address
+    Network(IpNet),
+    /// A hostname, e.g. `foo.example.com`.
+    /// A leading `*.` matches any subdomain, e.g. `*.example.com`
+    Host(String),
+}
+
+impl FromStr for UpstreamPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(net) = s.parse::<IpNet>() {
+            return Ok(Self::Network(net));
+        }
+
+        if let Ok(addr) = s.parse::<IpAddr>() {
+            return Ok(Self::Network(IpNet::from(addr)));
+        }
+
+        if s.is_empty() || s.contains('/') || s.contains(':') {
+            return Err(format!("Invalid upstream allowlist pattern: {s}"));
+        }
+
+        // A wildcard is only allowed as a whole leading label
+        let domain = s.strip_prefix("*.").unwrap_or(s);
+        if domain.is_empty() || domain.contains('*') {
+            return Err(format!("Invalid upstream allowlist pattern: {s}"));
+        }
+
+        Ok(Self::Host(s.to_lowercase()))
+    }
+}
+
+impl UpstreamPattern {
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            Self::Host(pattern) => match pattern.strip_prefix('*') {
+                Some(suffix) => host.ends_with(suffix) && host.len() > suffix.len(),
+                None => pattern.eq(host),
+            },
+            Self::Network(_) => false,
+        }
+    }
+
+    fn matches_addr(&self, addr: &IpAddr) -> bool {
+        match self {
+            Self::Network(net) => net.contains(addr),
+            Self::Host(_) => false,
+        }
+    }
+}
+
+/// The parsed patterns of the upstream allowlist
+#[derive(Debug, Clone)]
+pub struct Allowlist {
+    patterns: Vec<UpstreamPattern>,
+}
+
+impl Allowlist {
+    pub fn parse(patterns: &[String]) -> Result<Self, String> {
+        let patterns = patterns.iter()
+            .map(|x| x.parse())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            patterns,
+        })
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        self.patterns.iter().any(|x| x.matches_host(host))
+    }
+
+    fn matches_addr(&self, addr: &IpAddr) -> bool {
+        self.patterns.iter().any(|x| x.matches_addr(addr))
+    }
+
+    /// Check whether the upstream may be proxied to.
+    /// IP upstreams must be in one of the allowed networks.
+    /// Hostname upstreams must either match one of the allowed hostnames,
+    /// or all addresses they resolve to must be in one of the allowed networks.
+    /// The latter is checked when connecting to the upstream, by the [AllowlistResolver]
+    pub fn is_upstream_allowed(&self, upstream: &str) -> bool {
+        let url = match Url::parse(upstream) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+
+        let host = match url.host_str() {
+            Some(x) => x.trim_start_matches('[').trim_end_matches(']').to_lowercase(),
+            None => return false,
+        };
+
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return self.matches_addr(&addr);
+        }
+
+        self.matches_host(&host) || self.patterns.iter().any(|x| matches!(x, UpstreamPattern::Network(_)))
+    }
+}
+
+/// DNS resolver for the upstream clients which enforces the allowlist
+/// on the addresses that are actually connected to.
+/// Hostnames not matching one of the allowed hostnames may only
+/// resolve to addresses in one of the allowed networks
+#[derive(Debug)]
+pub struct AllowlistResolver {
+    allowlist: Arc<Allowlist>,
+}
+
+impl AllowlistResolver {
+    pub fn new(allowlist: Arc<Allowlist>) -> Self {
+        Self {
+            allowlist,
+        }
+    }
+}
+
+impl Resolve for AllowlistResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowlist = self.allowlist.clone();
+        let host = name.as_str().to_lowercase();
+
+        Box::pin(async move {
+            // The port is set by the client
+            let addrs = lookup_host((host.as_str(), 0)).await?.collect::<Vec<_>>();
+
+            if !allowlist.matches_host(&host) {
+                if let Some(addr) = addrs.iter().find(|x| !allowlist.matches_addr(&x.ip())) {
+                    warn!("Refusing to connect to upstream {host}, it resolves to {} which is not in the upstream allowlist", addr.ip());
+                    return Err(format!("Upstream {host} resolves to {}, which is not in the upstream allowlist", addr.ip()).into());
+                }
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(patterns: &[&str]) -> Allowlist {
+        let patterns = patterns.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        Allowlist::parse(&patterns).unwrap()
+    }
+
+    #[test]
+    fn parse_network() {
+        assert!(matches!("10.0.0.0/8".parse(), Ok(UpstreamPattern::Network(x)) if x == "10.0.0.0/8".parse::<IpNet>().unwrap()));
+        assert!(matches!("fd00::/8".parse(), Ok(UpstreamPattern::Network(x)) if x == "fd00::/8".parse::<IpNet>().unwrap()));
+    }
+
+    #[test]
+    fn parse_ip() {
+        assert!(matches!("10.1.2.3".parse(), Ok(UpstreamPattern::Network(x)) if x == "10.1.2.3/32".parse::<IpNet>().unwrap()));
+        assert!(matches!("::1".parse(), Ok(UpstreamPattern::Network(x)) if x == "::1/128".parse::<IpNet>().unwrap()));
+    }
+
+    #[test]
+    fn parse_host() {
+        assert!(matches!("Foo.Example.com".parse(), Ok(UpstreamPattern::Host(x)) if x == "foo.example.com"));
+        assert!(matches!("*.example.com".parse(), Ok(UpstreamPattern::Host(x)) if x == "*.example.com"));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for pattern in ["", "*", "*.", "*example.com", "foo.*.com", "*.*.example.com", "10.0.0.0/33", "example.com/8", "example.com:80"] {
+            assert!(pattern.parse::<UpstreamPattern>().is_err(), "{pattern:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        let allowlist = allowlist(&["*.example.com"]);
+        assert!(allowlist.matches_host("foo.example.com"));
+        assert!(allowlist.matches_host("foo.bar.example.com"));
+        assert!(!allowlist.matches_host("example.com"));
+        assert!(!allowlist.matches_host("evilexample.com"));
+    }
+
+    #[test]
+    fn ip_upstreams() {
+        let allowlist = allowlist(&["10.0.0.0/8", "::1", "example.com"]);
+        assert!(allowlist.is_upstream_allowed("http://10.1.2.3:8080"));
+        assert!(allowlist.is_upstream_allowed("http://[::1]:8080"));
+        assert!(!allowlist.is_upstream_allowed("http://192.168.1.1"));
+        assert!(!allowlist.is_upstream_allowed("http://[::2]"));
+    }
+
+    #[test]
+    fn host_upstreams_without_networks() {
+        let allowlist = allowlist(&["example.com", "*.internal.example.com"]);
+        assert!(allowlist.is_upstream_allowed("http://example.com"));
+        assert!(allowlist.is_upstream_allowed("https://Foo.Internal.Example.com:8443"));
+        assert!(!allowlist.is_upstream_allowed("http://other.example.com"));
+        assert!(!allowlist.is_upstream_allowed("http://10.0.0.1"));
+    }
+
+    #[test]
+    fn host_upstreams_with_networks() {
+        // Hostnames not in the allowlist are checked by the resolver
+        let allowlist = allowlist(&["10.0.0.0/8"]);
+        assert!(allowlist.is_upstream_allowed("http://other.example.com"));
+        assert!(!allowlist.matches_host("other.example.com"));
+    }
+
+    #[test]
+    fn invalid_upstreams() {
+        let allowlist = allowlist(&["10.0.0.0/8"]);
+        assert!(!allowlist.is_upstream_allowed("not a url"));
+        assert!(!allowlist.is_upstream_allowed("unix:/run/upstream.sock"));
+    }
+
+    #[tokio::test]
+    async fn resolver_checks_addresses() {
+        let resolver = AllowlistResolver::new(Arc::new(allowlist(&["10.0.0.0/8"])));
+        assert!(resolver.resolve("localhost".parse().unwrap()).await.is_err());
+
+        let resolver = AllowlistResolver::new(Arc::new(allowlist(&["127.0.0.0/8", "::1"])));
+        assert!(resolver.resolve("localhost".parse().unwrap()).await.is_ok());
+
+        let resolver = AllowlistResolver::new(Arc::new(allowlist(&["10.0.0.0/8", "localhost"])));
+        assert!(resolver.resolve("localhost".parse().unwrap()).await.is_ok());
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use actix_web::{web, HttpRequest};
+use actix_web::http::header::AUTHORIZATION;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::constant_time::verify_slices_are_equal;
+use ring::digest::{digest, Context, Digest, SHA256};
+use tracing::warn;
+use crate::config::BasicAuthConfig;
+
+/// The value of the `WWW-Authenticate` header when credentials are missing or invalid
+pub const WWW_AUTHENTICATE_BASIC: &str = "Basic realm=\"simpleproxy\", charset=\"UTF-8\"";
+
+/// The maximum number of verified credentials that are remembered
+const MAX_VERIFIED: usize = 1024;
+
+/// Credentials which were verified before, so the password hash
+/// only has to be computed once for every client.
+/// Keyed by [cache_key]
+static VERIFIED: Mutex<Option<HashSet<Vec<u8>>>> = Mutex::new(None);
+
+/// Check the HTTP Basic credentials in the `Authorization` header of the request.
+/// Credentials are compared in constant time. The password is verified on
+/// the blocking thread pool, so the worker can keep handling other requests
+pub async fn is_authorized(req: &HttpRequest, config: &BasicAuthConfig) -> bool {
+    let credentials = req.headers().get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, credentials)| STANDARD.decode(credentials.trim()).ok())
+        .and_then(|x| String::from_utf8(x).ok());
+
+    let credentials = match credentials {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let (username, password) = match credentials.split_once(':') {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let key = cache_key(&credentials, config);
+    if VERIFIED.lock().unwrap().as_ref().is_some_and(|x| x.contains(key.as_ref())) {
+        return true;
+    }
+
+    // Compare hashes, so the comparison doesn't depend on the length of the username either
+    let username_matches = verify_slices_are_equal(
+        digest(&SHA256, username.as_bytes()).as_ref(),
+        digest(&SHA256, config.username.as_bytes()).as_ref(),
+    ).is_ok();
+
+    // The password is always verified, so a wrong username takes as long as a wrong password
+    let password = password.to_string();
+    let password_hash = config.password_hash.clone();
+    let password_matches = match web::block(move || bcrypt::verify(password, &password_hash)).await {
+        Ok(x) => x.unwrap_or(false),
+        Err(e) => {
+            warn!("Failed to verify password: {e}");
+            false
+        }
+    };
+
+    let authorized = username_matches & password_matches;
+    if authorized {
+        let mut verified = VERIFIED.lock().unwrap();
+        let verified = verified.get_or_insert_with(HashSet::new);
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+
+        verified.insert(key.as_ref().to_vec());
+    }
+
+    authorized
+}
+
+/// The key of verified credentials in [VERIFIED].
+/// Includes the configured credentials, so changing them invalidates the entry
+fn cache_key(credentials: &str, config: &BasicAuthConfig) -> Digest {
+    let mut context = Context::new(&SHA256);
+    for part in [config.username.as_str(), config.password_hash.as_str(), credentials] {
+        context.update(&(part.len() as u64).to_be_bytes());
+        context.update(part.as_bytes());
+    }
+
+    context.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+
+    fn config() -> BasicAuthConfig {
+        BasicAuthConfig {
+            username: "user".to_string(),
+            // The lowest cost, to keep the tests fast
+            password_hash: bcrypt::hash("secret", 4).unwrap(),
+        }
+    }
+
+    fn request(authorization: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((AUTHORIZATION, authorization))
+            .to_http_request()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[actix_web::test]
+    async fn valid_credentials() {
+        assert!(is_authorized(&request(&basic("user:secret")), &config()).await);
+    }
+
+    #[actix_web::test]
+    async fn valid_credentials_2y_hash() {
+        // `htpasswd -B` writes `$2y$` hashes
+        let config = BasicAuthConfig {
+            username: "user".to_string(),
+            password_hash: bcrypt::hash_with_result("secret", 4).unwrap().format_for_version(bcrypt::Version::TwoY),
+        };
+        assert!(is_authorized(&request(&basic("user:secret")), &config).await);
+    }
+
+    #[actix_web::test]
+    async fn wrong_password() {
+        assert!(!is_authorized(&request(&basic("user:wrong")), &config()).await);
+    }
+
+    #[actix_web::test]
+    async fn wrong_username() {
+        assert!(!is_authorized(&request(&basic("other:secret")), &config()).await);
+    }
+
+    #[actix_web::test]
+    async fn not_basic_scheme() {
+        let credentials = STANDARD.encode("user:secret");
+        assert!(!is_authorized(&request(&format!("Bearer {credentials}")), &config()).await);
+    }
+
+    #[actix_web::test]
+    async fn invalid_base64() {
+        assert!(!is_authorized(&request("Basic not*base64"), &config()).await);
+    }
+
+    #[actix_web::test]
+    async fn missing_colon() {
+        assert!(!is_authorized(&request(&basic("usersecret")), &config()).await);
+    }
+
+    #[actix_web::test]
+    async fn verified_credentials_only_apply_to_their_config() {
+        let req = request(&basic("user:secret"));
+        let config = config();
+        assert!(is_authorized(&req, &config).await);
+        // Served from the verified credentials
+        assert!(is_authorized(&req, &config).await);
+
+        let other = BasicAuthConfig {
+            username: "user".to_string(),
+            password_hash: bcrypt::hash("other", 4).unwrap(),
+        };
+        assert!(!is_authorized(&req, &other).await);
+    }
+
+    #[actix_web::test]
+    async fn missing_header() {
+        let req = TestRequest::default().to_http_request();
+        assert!(!is_authorized(&req, &config()).await);
+    }
+}
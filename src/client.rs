@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::{Client, ClientBuilder};
+use reqwest::redirect::Policy;
+use crate::allowlist::AllowlistResolver;
+use crate::config::{Config, ProxyConfig, Route, UpstreamHttpVersion};
+
+/// The HTTP clients used to make requests to upstream servers.
+/// Clients are created once and shared between requests,
+/// so connections to upstream servers are pooled and reused.
+#[derive(Debug)]
+pub struct UpstreamClients {
+    default: Client,
+    /// Clients for routes which present a client certificate to the upstream server,
+    /// or use another HTTP version than the default
+    clients: HashMap<ClientKey, Client>,
+}
+
+/// The settings which require a separate client
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ClientKey {
+    /// The certificate and key paths of the client certificate
+    identity: Option<(PathBuf, Option<PathBuf>)>,
+    http_version: UpstreamHttpVersion,
+}
+
+impl ClientKey {
+    fn for_route(route: &Route, proxy_config: Option<&ProxyConfig>) -> Self {
+        Self {
+            identity: route.upstream_identity.as_ref().map(|x| (x.cert.clone(), x.key.clone())),
+            http_version: route.upstream_http_version(proxy_config),
+        }
+    }
+}
+
+impl UpstreamClients {
+    /// Create the clients for all routes in the configuration
+    pub fn new(config: &Config) -> reqwest::Result<Self> {
+        let default = client_builder(config, UpstreamHttpVersion::default()).build()?;
+
+        let mut clients = HashMap::new();
+        for route in &config.routes {
+            let key = ClientKey::for_route(route, config.proxy.as_ref());
+            if clients.contains_key(&key) {
+                continue;
+            }
+
+            let mut builder = client_builder(config, key.http_version);
+            if let Some(identity) = route.upstream_identity.as_ref().and_then(|x| x.identity.as_ref()) {
+                builder = builder.identity(identity.clone());
+            }
+
+            clients.insert(key, builder.build()?);
+        }
+
+        Ok(Self {
+            default,
+            clients,
+        })
+    }
+
+    /// Get the client to use for requests to the upstream of the route
+    pub fn for_route(&self, route: &Route, proxy_config: Option<&ProxyConfig>) -> &Client {
+        self.clients.get(&ClientKey::for_route(route, proxy_config))
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Create a client builder with the settings shared by all clients
+fn client_builder(config: &Config, http_version: UpstreamHttpVersion) -> ClientBuilder {
+    let mut builder = Client::builder()
+        .redirect(Policy::none())
+        .tcp_nodelay(config.net.tcp_nodelay());
+
+    builder = match http_version {
+        UpstreamHttpVersion::Http1 => builder.http1_only(),
+        UpstreamHttpVersion::Http2 => builder.http2_prior_knowledge(),
+        UpstreamHttpVersion::Auto => builder,
+    };
+
+    // Check the addresses upstream hostnames resolve to when connecting,
+    // so the upstream can't resolve to another address after it was checked
+    if let Some(allowlist) = config.proxy.as_ref().and_then(|x| x.allowlist.as_ref()) {
+        builder = builder.dns_resolver(Arc::new(AllowlistResolver::new(allowlist.clone())));
+    }
+
+    let pool_config = config.proxy.as_ref().and_then(|x| x.pool.as_ref());
+    if let Some(max_idle_per_host) = pool_config.and_then(|x| x.max_idle_per_host) {
+        builder = builder.pool_max_idle_per_host(max_idle_per_host);
+    }
+
+    if let Some(idle_timeout_secs) = pool_config.and_then(|x| x.idle_timeout_secs) {
+        builder = builder.pool_idle_timeout(Duration::from_secs(idle_timeout_secs));
+    }
+
+    builder
+}
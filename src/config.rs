@@ -1,9 +1,17 @@
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::allowlist::Allowlist;
+use crate::tls::{read_file_to_vec, TlsError};
+use reqwest::{Identity, Method};
+use reqwest::header::HeaderValue;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 use tokio::fs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::level_filters::LevelFilter;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -20,18 +28,160 @@ pub struct ProxyConfig {
     /// This makes it easy to track if the error
     /// occurred at the proxy (e.g. a misconfigured route)
     pub error_server_header: Option<String>,
+    /// If set, only upstreams matching one of these patterns may be proxied to.
+    /// Patterns are either networks in CIDR notation (e.g. `10.0.0.0/8`),
+    /// IP addresses, or hostnames, optionally with a leading wildcard (e.g. `*.example.com`).
+    /// Requests to other upstreams are refused with `502 Bad Gateway`
+    pub upstream_allowlist: Option<Vec<String>>,
+    /// The parsed `upstream_allowlist`.
+    /// This is populated when the configuration is loaded
+    #[serde(skip)]
+    pub allowlist: Option<Arc<Allowlist>>,
+    /// Request bodies larger than this number of bytes are spooled
+    /// to a temporary file, rather than being held in memory.
+    /// Defaults to never spooling request bodies, except on routes with multiple
+    /// upstream servers, where bodies larger than 1 MiB are spooled
+    pub body_spool_threshold: Option<usize>,
+    /// The directory spooled request bodies are written to.
+    /// Defaults to the system's temporary directory
+    pub body_spool_dir: Option<PathBuf>,
+    /// Upstream requests taking longer than this number of milliseconds
+    /// are logged as slow. Can be overridden per route.
+    /// Defaults to not logging slow requests
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Whether the `X-Forwarded-Port` and `X-Forwarded-Proto` headers sent by the client
+    /// should be trusted to determine the forwarded port. Enable this only if
+    /// the proxy itself is behind another, trusted, proxy. Defaults to `false`,
+    /// in which case the port of the listener is forwarded
+    pub trust_forwarded_port: Option<bool>,
+    /// The maximum total size in bytes of the headers of an upstream response.
+    /// Defaults to no limit
+    pub max_response_header_size: Option<usize>,
+    /// What to do with upstream responses exceeding `max_response_header_size`.
+    /// Defaults to `reject`
+    pub oversized_response_headers: Option<OversizedHeadersPolicy>,
+    /// Request methods for which the request body is dropped
+    /// before forwarding the request to the upstream server, e.g. `["GET", "DELETE"]`.
+    /// Can be overridden per route. Defaults to forwarding all bodies
+    pub drop_body_methods: Option<Vec<String>>,
+    /// Connection pool settings for connections to upstream servers
+    pub pool: Option<PoolConfig>,
+    /// The maximum size in bytes of a request body.
+    /// Larger requests are rejected with `413 Payload Too Large`,
+    /// or aborted if the body is already being streamed to the upstream server.
+    /// Defaults to no limit
+    pub max_request_body_size: Option<u64>,
+    /// The maximum size in bytes of an upstream response body.
+    /// Larger responses are rejected with `502 Bad Gateway`,
+    /// or aborted if the body is already being streamed to the client.
+    /// Defaults to no limit
+    pub max_response_body_size: Option<u64>,
+    /// The maximum number of milliseconds an upstream request may take,
+    /// including receiving the response body.
+    /// Requests exceeding it are answered with `504 Gateway Timeout`.
+    /// Can be overridden per route. Defaults to no timeout
+    pub timeout_ms: Option<u64>,
+    /// The HTTP version used for requests to upstream servers.
+    /// Can be overridden per route. Defaults to `http1`
+    pub http_version: Option<UpstreamHttpVersion>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PoolConfig {
+    /// The maximum number of idle connections kept open per upstream host.
+    /// Defaults to no limit
+    pub max_idle_per_host: Option<usize>,
+    /// The number of seconds after which idle connections are closed.
+    /// Defaults to 90 seconds
+    pub idle_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedHeadersPolicy {
+    /// Respond with `502 Bad Gateway`
+    #[default]
+    Reject,
+    /// Drop the largest headers until the limit is satisfied
+    Truncate,
+}
+
+/// One or more upstream servers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Upstreams {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Upstreams {
+    pub fn as_slice(&self) -> &[String] {
+        match self {
+            Self::Single(x) => std::slice::from_ref(x),
+            Self::Multiple(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for Upstreams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_slice().join(", "))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamPolicy {
+    /// Always start at the first upstream server,
+    /// only trying the next one if it fails
+    #[default]
+    Failover,
+    /// Start at the next upstream server for every request,
+    /// distributing requests evenly over all upstream servers
+    RoundRobin,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamHttpVersion {
+    /// Only use HTTP/1.1
+    #[default]
+    Http1,
+    /// Only use HTTP/2, without negotiating it first (prior knowledge).
+    /// The upstream server must support HTTP/2, also over plain HTTP.
+    /// WebSocket connections can't be proxied with HTTP/2
+    Http2,
+    /// Negotiate the HTTP version with the upstream server using ALPN.
+    /// This only has effect for HTTPS upstream servers, plain HTTP uses HTTP/1.1
+    Auto,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetConfig {
     pub port: u16,
     pub bind_address: String,
+    /// Whether `TCP_NODELAY` should be set, disabling Nagle's algorithm,
+    /// on connections accepted from clients and on connections to upstream servers.
+    /// Defaults to `true`
+    pub tcp_nodelay: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TlsConfig {
-    pub pubkey: PathBuf,
-    pub privkey: PathBuf,
+    /// The certificate (chain).
+    /// Must be specified together with `privkey`
+    pub pubkey: Option<PathBuf>,
+    /// The private key belonging to the certificate.
+    /// Must be specified together with `pubkey`
+    pub privkey: Option<PathBuf>,
+    /// A single PEM file containing both the certificate (chain)
+    /// and the private key. Mutually exclusive with `pubkey` and `privkey`
+    pub pem: Option<PathBuf>,
+    /// Whether the host of a request must match the server name
+    /// the client indicated (SNI) during the TLS handshake.
+    /// On a mismatch `421 Misdirected Request` is returned.
+    /// Defaults to `false`
+    pub strict_sni: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,7 +190,9 @@ pub struct Route {
     /// E.g. setting this to `/foo` will route `/foo/bar`, `/foo/foo/bar` to this route,
     /// but `/bar/foo` will not be routed to this route.
     pub path_prefix: Option<String>,
-    /// The host this route matches on. E.g. `foo.example.com`
+    /// The host this route matches on. E.g. `foo.example.com`.
+    /// A leading `*.` matches any subdomain, e.g. `*.example.com`.
+    /// Exact matches take priority over wildcard matches
     pub host: Option<String>,
     /// Whether this should be the default route (i.e. fallback)
     /// if no other route matches. Only one route may have this
@@ -48,21 +200,95 @@ pub struct Route {
     /// there may only be 1 default route.
     pub default: Option<bool>,
     /// The upstream server
-    /// This includes the protocol, e.g. `https://`
-    pub upstream: String,
+    /// This includes the protocol, e.g. `https://`.
+    /// May also be a list of upstream servers, which are tried according to `upstream_policy`
+    pub upstream: Upstreams,
+    /// How the upstream servers are chosen if multiple are configured.
+    /// Connection errors and `5xx` responses cause the next upstream server to be tried.
+    /// Defaults to `failover`
+    pub upstream_policy: Option<UpstreamPolicy>,
+    /// The upstream server the next request starts at with the `round_robin` policy
+    #[serde(skip)]
+    pub next_upstream: Arc<AtomicUsize>,
     /// Whether the `path_prefix` should be stripped from the request path
     /// E.g. if the `path_prefix` is `/foo`, and the request path is `/foo/bar`,
     /// with this option enabled the path becomes just `/bar`
     pub strip_path_prefix: Option<bool>,
-    // TODO support authorization
+    /// Override the log level for requests matched to this route,
+    /// e.g. `warn` to silence a noisy route or `trace` for debugging it.
+    /// Defaults to the level configured with `RUST_LOG`
+    pub log_level: Option<String>,
+    /// Client certificate to present to the upstream server (mTLS)
+    pub upstream_identity: Option<UpstreamIdentityConfig>,
+    /// Whether identical concurrent `GET` and `HEAD` requests should be coalesced
+    /// into a single upstream request, of which the response is shared.
+    /// Requests are identical if their method, host, path, query and the headers
+    /// listed in `single_flight_headers` are equal. Defaults to `false`
+    pub single_flight: Option<bool>,
+    /// Request headers which are taken into account when determining
+    /// whether requests are identical in single-flight mode
+    pub single_flight_headers: Option<Vec<String>>,
+    /// Whether the `Host` header forwarded to the upstream server
+    /// should be lowercased. Defaults to `false`, forwarding it unchanged
+    pub lowercase_host: Option<bool>,
+    /// Upstream requests for this route taking longer than this number
+    /// of milliseconds are logged as slow.
+    /// Defaults to `slow_request_threshold_ms` of the proxy configuration
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Overwrite the `Accept-Encoding` header sent to the upstream server,
+    /// e.g. `identity` to request uncompressed responses.
+    /// Defaults to passing through the client's header
+    pub accept_encoding: Option<String>,
+    /// Whether the `Accept-Encoding` header should be removed from the request
+    /// to the upstream server. Takes precedence over `accept_encoding`.
+    /// Defaults to `false`
+    pub strip_accept_encoding: Option<bool>,
+    /// Request methods for which the request body is dropped
+    /// before forwarding the request to the upstream server.
+    /// Defaults to `drop_body_methods` of the proxy configuration
+    pub drop_body_methods: Option<Vec<String>>,
+    /// The maximum number of milliseconds an upstream request for this route may take.
+    /// Defaults to `timeout_ms` of the proxy configuration
+    pub timeout_ms: Option<u64>,
+    /// The HTTP version used for requests to the upstream server of this route.
+    /// Defaults to `http_version` of the proxy configuration
+    pub http_version: Option<UpstreamHttpVersion>,
+    /// Require HTTP Basic authentication for requests to this route.
+    /// Requests without valid credentials are answered with `401 Unauthorized`.
+    /// The `Authorization` header is never forwarded to the upstream server
+    pub basic_auth: Option<BasicAuthConfig>,
     /// Optional headers to insert into the response back to the client
     pub response_headers: Option<HashMap<String, String>>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BasicAuthConfig {
+    pub username: String,
+    /// The bcrypt hash of the password, as found in htpasswd files (`$2b$...`),
+    /// e.g. generated with `htpasswd -nbB username 'password'`
+    pub password_hash: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamIdentityConfig {
+    /// PEM file containing the client certificate (chain).
+    /// This file may also contain the private key,
+    /// in which case `key` can be omitted
+    pub cert: PathBuf,
+    /// PEM file containing the private key belonging to `cert`
+    pub key: Option<PathBuf>,
+    /// The identity loaded from `cert` and `key`.
+    /// This is populated when the configuration is loaded
+    #[serde(skip)]
+    pub identity: Option<Identity>,
+}
+
 #[derive(Debug, Error)]
 pub enum ConfigError {
     #[error("{0}")]
     Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Tls(#[from] TlsError),
     #[error("Failed to serialize: {0}")]
     TomlSer(#[from] toml::ser::Error),
     #[error("Failed to deserialize: {0}")]
@@ -71,6 +297,8 @@ pub enum ConfigError {
     FileNotFound(PathBuf),
     #[error("Invalid configuration: {0}")]
     InvalidConfig(String),
+    #[error("Invalid upstream client certificate {0}: {1}")]
+    InvalidIdentity(PathBuf, reqwest::Error),
 }
 
 impl Default for Config {
@@ -91,6 +319,7 @@ impl Default for NetConfig {
         Self {
             port: 8080,
             bind_address: "0.0.0.0".into(),
+            tcp_nodelay: Some(true),
         }
     }
 }
@@ -98,8 +327,10 @@ impl Default for NetConfig {
 impl Default for TlsConfig {
     fn default() -> Self {
         Self {
-            privkey: PathBuf::from("/etc/your/priv/key.pem"),
-            pubkey: PathBuf::from("/etc/your/pub/key.pem"),
+            privkey: Some(PathBuf::from("/etc/your/priv/key.pem")),
+            pubkey: Some(PathBuf::from("/etc/your/pub/key.pem")),
+            pem: None,
+            strict_sni: Some(false),
         }
     }
 }
@@ -109,14 +340,65 @@ impl Default for Route {
         Self {
             host: Some("foo.example.com".into()),
             path_prefix: Some("/bar".into()),
-            upstream: "http://foo-bar.internal.example.com:8080".into(),
+            upstream: Upstreams::Single("http://foo-bar.internal.example.com:8080".into()),
+            upstream_policy: None,
+            next_upstream: Arc::default(),
             default: Some(false),
             strip_path_prefix: Some(false),
+            log_level: None,
+            upstream_identity: None,
+            single_flight: Some(false),
+            single_flight_headers: None,
+            lowercase_host: Some(false),
+            slow_request_threshold_ms: None,
+            accept_encoding: None,
+            strip_accept_encoding: Some(false),
+            drop_body_methods: None,
+            timeout_ms: None,
+            http_version: None,
+            basic_auth: None,
             response_headers: Some(vec![("X-Foo".to_string(), "Bar".to_string())].into_iter().collect())
         }
     }
 }
 
+impl Route {
+    /// The log level override for this route, if configured
+    pub fn log_level_filter(&self) -> Option<LevelFilter> {
+        self.log_level.as_ref()
+            .and_then(|x| x.parse().ok())
+    }
+
+    /// The HTTP version to use for requests to the upstream server of this route
+    pub fn upstream_http_version(&self, proxy_config: Option<&ProxyConfig>) -> UpstreamHttpVersion {
+        self.http_version
+            .or(proxy_config.and_then(|x| x.http_version))
+            .unwrap_or_default()
+    }
+
+    /// The upstream servers of this route, in the order they should be tried for a request
+    pub fn upstreams_in_order(&self) -> Vec<&str> {
+        let upstreams = self.upstream.as_slice();
+        let start = match self.upstream_policy.unwrap_or_default() {
+            UpstreamPolicy::Failover => 0,
+            UpstreamPolicy::RoundRobin if upstreams.is_empty() => 0,
+            UpstreamPolicy::RoundRobin => self.next_upstream.fetch_add(1, Ordering::Relaxed) % upstreams.len(),
+        };
+
+        upstreams[start..].iter()
+            .chain(&upstreams[..start])
+            .map(|x| x.as_str())
+            .collect()
+    }
+}
+
+impl NetConfig {
+    /// Whether `TCP_NODELAY` should be set on inbound and outbound sockets
+    pub fn tcp_nodelay(&self) -> bool {
+        self.tcp_nodelay.unwrap_or(true)
+    }
+}
+
 impl Config {
     pub async fn new<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
         let path = path.as_ref();
@@ -128,23 +410,109 @@ impl Config {
         let mut buf = Vec::new();
         f.read_to_end(&mut buf).await?;
 
-        let deserialized: Self = toml::de::from_slice(&buf)?;
+        let mut deserialized: Self = toml::de::from_slice(&buf)?;
         deserialized.validate()?;
+        deserialized.load_upstream_allowlist()?;
+        deserialized.load_upstream_identities().await?;
 
         Ok(deserialized)
     }
 
+    /// Parse the patterns of the upstream allowlist
+    fn load_upstream_allowlist(&mut self) -> Result<(), ConfigError> {
+        if let Some(proxy) = &mut self.proxy {
+            if let Some(allowlist) = &proxy.upstream_allowlist {
+                let allowlist = Allowlist::parse(allowlist)
+                    .map_err(ConfigError::InvalidConfig)?;
+                proxy.allowlist = Some(Arc::new(allowlist));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the upstream client certificates configured on the routes
+    async fn load_upstream_identities(&mut self) -> Result<(), ConfigError> {
+        for route in &mut self.routes {
+            let identity_config = match &mut route.upstream_identity {
+                Some(x) => x,
+                None => continue,
+            };
+
+            let mut pem = read_file_to_vec(&identity_config.cert).await?;
+            if let Some(key) = &identity_config.key {
+                pem.push(b'\n');
+                pem.extend(read_file_to_vec(key).await?);
+            }
+
+            let identity = Identity::from_pem(&pem)
+                .map_err(|e| ConfigError::InvalidIdentity(identity_config.cert.clone(), e))?;
+            identity_config.identity = Some(identity);
+        }
+
+        Ok(())
+    }
+
     fn validate(&self) -> Result<(), ConfigError> {
         if let Some(tls) = &self.tls {
-            if !tls.pubkey.exists() {
-                return Err(ConfigError::FileNotFound(tls.pubkey.clone()));
+            let paths = match (&tls.pem, &tls.pubkey, &tls.privkey) {
+                (Some(pem), None, None) => vec![pem],
+                (None, Some(pubkey), Some(privkey)) => vec![pubkey, privkey],
+                _ => return Err(ConfigError::InvalidConfig("TLS requires either `pem`, or both `pubkey` and `privkey` to be specified".into())),
+            };
+
+            for path in paths {
+                if !path.exists() {
+                    return Err(ConfigError::FileNotFound(path.clone()));
+                }
             }
+        }
+
+        if let Some(methods) = self.proxy.as_ref().and_then(|x| x.drop_body_methods.as_ref()) {
+            validate_methods(methods)?;
+        }
 
-            if !tls.privkey.exists() {
-                return Err(ConfigError::FileNotFound(tls.privkey.clone()));
+        if let Some(body_spool_dir) = self.proxy.as_ref().and_then(|x| x.body_spool_dir.as_ref()) {
+            if !body_spool_dir.is_dir() {
+                return Err(ConfigError::FileNotFound(body_spool_dir.clone()));
             }
         }
 
+        for route in &self.routes {
+            if route.upstream.as_slice().is_empty() {
+                return Err(ConfigError::InvalidConfig("Routes must have at least one upstream".into()));
+            }
+
+            if let Some(log_level) = &route.log_level {
+                if log_level.parse::<LevelFilter>().is_err() {
+                    return Err(ConfigError::InvalidConfig(format!("Invalid log level for route with upstream {}: {log_level}", route.upstream)));
+                }
+            }
+
+            if let Some(accept_encoding) = &route.accept_encoding {
+                if HeaderValue::from_str(accept_encoding).is_err() {
+                    return Err(ConfigError::InvalidConfig(format!("Invalid `accept_encoding` for route with upstream {}: {accept_encoding}", route.upstream)));
+                }
+            }
+
+            if let Some(methods) = &route.drop_body_methods {
+                validate_methods(methods)?;
+            }
+
+            if let Some(basic_auth) = &route.basic_auth {
+                if basic_auth.password_hash.parse::<bcrypt::HashParts>().is_err() {
+                    return Err(ConfigError::InvalidConfig(format!("Invalid `password_hash` for route with upstream {}, expected a bcrypt hash", route.upstream)));
+                }
+            }
+
+            if let Some(identity) = &route.upstream_identity {
+                for path in std::iter::once(&identity.cert).chain(identity.key.as_ref()) {
+                    if !path.exists() {
+                        return Err(ConfigError::FileNotFound(path.clone()));
+                    }
+                }
+            }
+        }
 
         // Check the `default` parameter
         let mut host_default_count: HashMap<&str, usize> = HashMap::with_capacity(self.routes.len());
@@ -189,3 +557,122 @@ impl Config {
         Ok(this)
     }
 }
+
+/// Read a file at the provided path into a Vec of bytes.
+/// Check that all entries of `drop_body_methods` are valid method names
+fn validate_methods(methods: &[String]) -> Result<(), ConfigError> {
+    match methods.iter().find(|x| Method::from_bytes(x.as_bytes()).is_err()) {
+        Some(method) => Err(ConfigError::InvalidConfig(format!("Invalid method in `drop_body_methods`: {method:?}"))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(upstream: Upstreams, upstream_policy: Option<UpstreamPolicy>) -> Route {
+        Route {
+            upstream,
+            upstream_policy,
+            ..Route::default()
+        }
+    }
+
+    fn multiple(upstreams: &[&str]) -> Upstreams {
+        Upstreams::Multiple(upstreams.iter().map(|x| x.to_string()).collect())
+    }
+
+    #[test]
+    fn upstream_deserialized_from_string() {
+        let route: Route = toml::from_str("upstream = 'http://a'").unwrap();
+        assert!(matches!(&route.upstream, Upstreams::Single(x) if x == "http://a"));
+        assert_eq!(route.upstream.as_slice(), ["http://a"]);
+    }
+
+    #[test]
+    fn upstream_deserialized_from_list() {
+        let route: Route = toml::from_str("upstream = ['http://a', 'http://b']").unwrap();
+        assert!(matches!(route.upstream, Upstreams::Multiple(_)));
+        assert_eq!(route.upstream.as_slice(), ["http://a", "http://b"]);
+    }
+
+    #[test]
+    fn upstreams_in_order_failover() {
+        let route = route(multiple(&["http://a", "http://b", "http://c"]), None);
+        for _ in 0..3 {
+            assert_eq!(route.upstreams_in_order(), ["http://a", "http://b", "http://c"]);
+        }
+
+        let route = Route {
+            upstream_policy: Some(UpstreamPolicy::Failover),
+            ..route
+        };
+        assert_eq!(route.upstreams_in_order(), ["http://a", "http://b", "http://c"]);
+    }
+
+    #[test]
+    fn upstreams_in_order_round_robin() {
+        let route = route(multiple(&["http://a", "http://b", "http://c"]), Some(UpstreamPolicy::RoundRobin));
+        assert_eq!(route.upstreams_in_order(), ["http://a", "http://b", "http://c"]);
+        assert_eq!(route.upstreams_in_order(), ["http://b", "http://c", "http://a"]);
+        assert_eq!(route.upstreams_in_order(), ["http://c", "http://a", "http://b"]);
+        assert_eq!(route.upstreams_in_order(), ["http://a", "http://b", "http://c"]);
+    }
+
+    #[test]
+    fn upstreams_in_order_single() {
+        let route = route(Upstreams::Single("http://a".into()), Some(UpstreamPolicy::RoundRobin));
+        assert_eq!(route.upstreams_in_order(), ["http://a"]);
+        assert_eq!(route.upstreams_in_order(), ["http://a"]);
+    }
+
+    fn config_with_route(route: Route) -> Config {
+        Config {
+            tls: None,
+            routes: vec![route],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn invalid_accept_encoding_rejected() {
+        let config = config_with_route(Route {
+            accept_encoding: Some("gzip\n".to_string()),
+            ..Route::default()
+        });
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidConfig(_))));
+
+        let config = config_with_route(Route {
+            accept_encoding: Some("gzip, br".to_string()),
+            ..Route::default()
+        });
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_drop_body_methods_rejected() {
+        for methods in [vec!["GET".to_string(), "NOT A METHOD".to_string()], vec![String::new()]] {
+            let config = config_with_route(Route {
+                drop_body_methods: Some(methods),
+                ..Route::default()
+            });
+            assert!(matches!(config.validate(), Err(ConfigError::InvalidConfig(_))));
+        }
+
+        let config = Config {
+            proxy: Some(ProxyConfig {
+                drop_body_methods: Some(vec!["DEL ETE".to_string()]),
+                ..ProxyConfig::default()
+            }),
+            ..config_with_route(Route::default())
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidConfig(_))));
+
+        let config = config_with_route(Route {
+            drop_body_methods: Some(vec!["get".to_string(), "DELETE".to_string()]),
+            ..Route::default()
+        });
+        assert!(config.validate().is_ok());
+    }
+}
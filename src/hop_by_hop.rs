@@ -0,0 +1,45 @@
+use actix_web::http::header::{
+    HeaderName, HeaderValue, CONNECTION, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TE, TRAILER,
+    TRANSFER_ENCODING, UPGRADE,
+};
+
+/// Headers which only apply to a single connection (RFC 7230, section 6.1),
+/// and as such must not be forwarded by a proxy
+const HOP_BY_HOP_HEADERS: [HeaderName; 9] = [
+    CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// The hop-by-hop headers of a message.
+/// Besides the standard set, this includes the headers listed in the `Connection` header of the message
+#[derive(Debug)]
+pub struct HopByHop {
+    connection_headers: Vec<HeaderName>,
+}
+
+impl HopByHop {
+    /// Collect the hop-by-hop headers from the values of the `Connection` header of a message
+    pub fn new<'a, I: IntoIterator<Item = &'a HeaderValue>>(connection: I) -> Self {
+        let connection_headers = connection.into_iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+            .filter_map(|x| HeaderName::from_bytes(x.trim().as_bytes()).ok())
+            .collect();
+
+        Self {
+            connection_headers,
+        }
+    }
+
+    /// Whether the header must not be forwarded
+    pub fn contains(&self, name: &HeaderName) -> bool {
+        HOP_BY_HOP_HEADERS.contains(name) || self.connection_headers.contains(name)
+    }
+}
@@ -0,0 +1,115 @@
+use std::future::Future;
+use std::sync::RwLock;
+use tracing::{span, Event, Metadata, Subscriber};
+use tracing::level_filters::LevelFilter;
+use tracing::subscriber::Interest;
+use tracing_subscriber::layer::{Context, Filter};
+
+tokio::task_local! {
+    /// The log level override of the route the current request was matched to
+    static ROUTE_LOG_LEVEL: LevelFilter;
+}
+
+/// The most and the least verbose log level overrides of the configured routes,
+/// if any route has an override
+static ROUTE_LEVELS: RwLock<Option<(LevelFilter, LevelFilter)>> = RwLock::new(None);
+
+/// Set the log level overrides of the configured routes.
+/// This must be called whenever the configuration is loaded, so [RouteLevelFilter]
+/// knows which callsites a route may enable or disable
+pub fn set_route_levels<I: IntoIterator<Item = LevelFilter>>(levels: I) {
+    let levels = levels.into_iter().fold(None, |acc, level| match acc {
+        Some((most, least)) => Some((level.max(most), level.min(least))),
+        None => Some((level, level)),
+    });
+
+    *ROUTE_LEVELS.write().unwrap() = levels;
+    // The interest of callsites depends on the route levels
+    tracing::callsite::rebuild_interest_cache();
+}
+
+/// Run the provided future with the route's log level override, if any.
+/// While the future is being polled, [RouteLevelFilter] uses the override
+/// instead of the globally configured filter.
+pub async fn with_route_level<F: Future>(level: Option<LevelFilter>, fut: F) -> F::Output {
+    match level {
+        Some(level) => ROUTE_LOG_LEVEL.scope(level, fut).await,
+        None => fut.await,
+    }
+}
+
+/// Filter which applies the log level override of the matched route,
+/// falling back to the wrapped filter outside of a route or if the route
+/// has no override configured.
+pub struct RouteLevelFilter<F> {
+    inner: F,
+}
+
+impl<F> RouteLevelFilter<F> {
+    pub fn new(inner: F) -> Self {
+        Self {
+            inner,
+        }
+    }
+}
+
+impl<S: Subscriber, F: Filter<S>> Filter<S> for RouteLevelFilter<F> {
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        match ROUTE_LOG_LEVEL.try_with(|level| *level) {
+            Ok(level) => level >= *meta.level(),
+            Err(_) => self.inner.enabled(meta, cx),
+        }
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let interest = self.inner.callsite_enabled(meta);
+        let (most_verbose, least_verbose) = match *ROUTE_LEVELS.read().unwrap() {
+            Some(x) => x,
+            None => return interest,
+        };
+
+        // A route may enable a callsite the inner filter disables, or the other way around
+        let route_enables = interest.is_never() && most_verbose >= *meta.level();
+        let route_disables = interest.is_always() && least_verbose < *meta.level();
+        if route_enables || route_disables {
+            Interest::sometimes()
+        } else {
+            interest
+        }
+    }
+
+    fn event_enabled(&self, event: &Event<'_>, cx: &Context<'_, S>) -> bool {
+        match ROUTE_LOG_LEVEL.try_with(|_| ()) {
+            Ok(_) => true,
+            Err(_) => self.inner.event_enabled(event, cx),
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        let inner = self.inner.max_level_hint()?;
+        match *ROUTE_LEVELS.read().unwrap() {
+            Some((most_verbose, _)) => Some(inner.max(most_verbose)),
+            None => Some(inner),
+        }
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_new_span(attrs, id, ctx)
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        self.inner.on_record(id, values, ctx)
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx)
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx)
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx)
+    }
+}
@@ -1,15 +1,26 @@
+mod allowlist;
 mod args;
+mod auth;
+mod client;
 mod config;
+mod hop_by_hop;
+mod logging;
 mod proxy;
+mod reload;
+mod singleflight;
 mod tls;
+mod websocket;
 
 use crate::args::Args;
+use crate::client::UpstreamClients;
 use crate::config::Config;
+use crate::reload::ReloadableConfig;
+use crate::singleflight::SingleFlight;
 use actix_web::{web, App, HttpServer, Route};
 use std::process::exit;
 use tracing::error;
-use tracing_actix_web::RootSpanBuilder;
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
@@ -24,29 +35,58 @@ async fn main() -> std::io::Result<()> {
         }
     };
 
+    logging::set_route_levels(config.routes.iter().filter_map(|x| x.log_level_filter()));
+
     // Configure TLS, if needed
     let tls_config = match &config.tls {
-        Some(config) => match tls::configure_tls(&config.pubkey, &config.privkey).await {
-            Ok(x) => Some(x),
-            Err(e) => {
-                error!("Failed to configure TLS: {e}");
-                exit(1);
+        Some(config) => {
+            let tls_config = match (&config.pem, &config.pubkey, &config.privkey) {
+                (Some(pem), _, _) => tls::configure_tls_combined(pem).await,
+                (None, Some(pubkey), Some(privkey)) => tls::configure_tls(pubkey, privkey).await,
+                // Checked during config validation
+                _ => unreachable!(),
+            };
+
+            match tls_config {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    error!("Failed to configure TLS: {e}");
+                    exit(1);
+                }
             }
         },
         None => None,
     };
 
-    let appdata = web::Data::new(config.clone());
+    let clients = match UpstreamClients::new(&config) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Failed to create upstream HTTP client: {e}");
+            exit(1);
+        }
+    };
+
+    let net_config = config.net.clone();
+    let appdata = web::Data::new(ReloadableConfig::new(args.config.into(), config, clients));
+    if let Err(e) = reload::reload_on_sighup(appdata.clone()) {
+        error!("Failed to listen for SIGHUP: {e}");
+        exit(1);
+    }
+
+    let single_flight = web::Data::new(SingleFlight::default());
     let http_server = HttpServer::new(move || {
         App::new()
             .wrap(tracing_actix_web::TracingLogger::default())
             .app_data(appdata.clone())
+            .app_data(single_flight.clone())
             .default_service(Route::new().to(proxy::proxy))
-    });
+    })
+        .on_connect(tls::on_connect)
+        .tcp_nodelay(net_config.tcp_nodelay());
 
     // Bind the server to the provided bind address and port
     // Configure TLS as the user specifies
-    let bind_url = format!("{}:{}", config.net.bind_address, config.net.port);
+    let bind_url = format!("{}:{}", net_config.bind_address, net_config.port);
     let http_server = if let Some(tls_config) = tls_config {
         http_server.bind_rustls(bind_url, tls_config)
     } else {
@@ -68,8 +108,10 @@ async fn main() -> std::io::Result<()> {
 fn configure_tracing() {
 
     let tracing_sub = tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().compact())
-        .with(tracing_subscriber::EnvFilter::from_env("RUST_LOG"));
+        .with(tracing_subscriber::fmt::layer()
+            .compact()
+            .with_filter(logging::RouteLevelFilter::new(tracing_subscriber::EnvFilter::from_env("RUST_LOG")))
+        );
 
     tracing::subscriber::set_global_default(tracing_sub).expect("configuring tracing");
 }
@@ -1,40 +1,150 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::io;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use crate::Config;
-use actix_web::{web, HttpRequest, HttpResponse};
-use actix_web::http::header::{HeaderName, HeaderValue};
+use crate::{auth, logging, websocket};
+use crate::hop_by_hop::HopByHop;
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::http::header::{HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, UPGRADE, WWW_AUTHENTICATE};
+use actix_web::body::{BodyStream, SizedStream};
+use actix_web::http::Method;
+use actix_web::web::Bytes;
 use anyhow::Result;
 use futures_util::StreamExt;
-use reqwest::{Client, Response, StatusCode, Version};
-use reqwest::redirect::Policy;
-use tracing::{warn, instrument, debug, trace};
-use crate::config::{ProxyConfig, Route};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use reqwest::header::HeaderMap;
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{warn, instrument, debug, info, trace};
+use tracing::level_filters::LevelFilter;
+use crate::reload::ReloadableConfig;
+use crate::config::{OversizedHeadersPolicy, ProxyConfig, Route, TlsConfig};
+use crate::singleflight::{BufferedResponse, SharedError, SharedResult, SingleFlight};
+use crate::tls::SniHostname;
 
-#[instrument(skip(data, req, payload))]
+#[instrument(skip(config, single_flight, req, payload))]
 pub async fn proxy(
-    data: web::Data<Config>,
+    config: web::Data<ReloadableConfig>,
+    single_flight: web::Data<SingleFlight>,
     req: HttpRequest,
     payload: web::Payload
 ) -> HttpResponse {
+    let mut access_log = AccessLog::new();
+    let response = proxy_request(&mut access_log, config, single_flight, req.clone(), payload).await;
+
+    // The access log event is subject to the log level of the route
+    let level = access_log.route.as_ref().and_then(|x| x.log_level);
+    logging::with_route_level(level, async { access_log.log(&req, &response) }).await;
+    response
+}
+
+/// The details of a request, which are logged once the request is handled
+#[derive(Debug)]
+struct AccessLog {
+    started: Instant,
+    host: Option<String>,
+    route: Option<AccessLogRoute>,
+    /// The upstream server the request was sent to
+    upstream: Option<String>,
+    upstream_status: Option<StatusCode>,
+}
+
+/// The route chosen for a request
+#[derive(Debug)]
+struct AccessLogRoute {
+    route_match: RouteMatch,
+    path_prefix: String,
+    host: String,
+    log_level: Option<LevelFilter>,
+}
+
+impl AccessLogRoute {
+    fn new(route: &Route, route_match: RouteMatch) -> Self {
+        Self {
+            route_match,
+            path_prefix: route.path_prefix.clone().unwrap_or_default(),
+            host: route.host.clone().unwrap_or_default(),
+            log_level: route.log_level_filter(),
+        }
+    }
+}
+
+impl AccessLog {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            host: None,
+            route: None,
+            upstream: None,
+            upstream_status: None,
+        }
+    }
+
+    /// Emit the access log event for the request
+    fn log(&self, req: &HttpRequest, response: &HttpResponse) {
+        let route = self.route.as_ref();
+        let upstream = match (&self.upstream, route) {
+            (Some(upstream), _) => upstream.as_str(),
+            (None, Some(_)) => "none",
+            (None, None) => "no route",
+        };
+
+        info!(
+            method = %req.method(),
+            host = self.host.as_deref().unwrap_or(""),
+            path = req.path(),
+            upstream,
+            route_match = route.map(|x| x.route_match.as_str()).unwrap_or("none"),
+            route = route.map(|x| x.path_prefix.as_str()).unwrap_or(""),
+            route_host = route.map(|x| x.host.as_str()).unwrap_or(""),
+            status = response.status().as_u16(),
+            upstream_status = self.upstream_status.map(|x| x.as_u16()),
+            latency_ms = self.started.elapsed().as_millis() as u64,
+            "Proxied request"
+        );
+    }
+}
+
+async fn proxy_request(
+    access_log: &mut AccessLog,
+    config: web::Data<ReloadableConfig>,
+    single_flight: web::Data<SingleFlight>,
+    req: HttpRequest,
+    payload: web::Payload
+) -> HttpResponse {
+    let snapshot = config.load();
+    let data = &snapshot.config;
+    let clients = &snapshot.clients;
+
     let path = req.path();
     let host = match get_request_host(&req) {
         Some(x) => x,
         None => return HttpResponse::new(StatusCode::BAD_GATEWAY)
     };
 
-    let body = match extract_body(payload).await {
-        Ok(x) => x,
-        Err(e) => {
-            warn!("Failed to extract request body: {e}");
-            return HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE);
-        }
-    };
+    if !sni_matches_host(&req, &host, data.tls.as_ref()) {
+        debug!("Request host does not match the SNI hostname");
+        return HttpResponse::build(StatusCode::MISDIRECTED_REQUEST)
+            .insert_header(("Server", get_server_header(data.proxy.as_ref())))
+            .finish();
+    }
 
     debug!("path: {path}");
     debug!("host: {host}");
+    access_log.host = Some(host.clone());
 
     let route = match choose_route(&host, path, data.routes.iter().collect::<Vec<_>>()) {
-        Some(x) => x,
+        Some((route, route_match)) => {
+            access_log.route = Some(AccessLogRoute::new(route, route_match));
+            route
+        },
         None => {
             debug!("Could not find route");
             return HttpResponse::build(StatusCode::NOT_FOUND)
@@ -43,37 +153,237 @@ pub async fn proxy(
         }
     };
 
-    // Make the request to the upstream server
-    let reqwest_response = make_request(
-        req.clone(),
-        build_request_path(path, &route).as_ref(),
-        body.clone(),
-        &route.upstream,
-        &host,
-    ).await;
+    logging::with_route_level(route.log_level_filter(), async {
+        if let Some(basic_auth) = &route.basic_auth {
+            if !auth::is_authorized(&req, basic_auth).await {
+                debug!("Request is missing valid Basic credentials");
+                return HttpResponse::build(StatusCode::UNAUTHORIZED)
+                    .insert_header(("Server", get_server_header(data.proxy.as_ref())))
+                    .insert_header((WWW_AUTHENTICATE, auth::WWW_AUTHENTICATE_BASIC))
+                    .finish();
+            }
+        }
+
+        if let Some(allowlist) = data.proxy.as_ref().and_then(|x| x.allowlist.as_ref()) {
+            for upstream in route.upstream.as_slice() {
+                if !allowlist.is_upstream_allowed(upstream) {
+                    warn!("Refusing to proxy to upstream {upstream}, it is not in the upstream allowlist");
+                    return HttpResponse::build(StatusCode::BAD_GATEWAY)
+                        .insert_header(("Server", get_server_header(data.proxy.as_ref())))
+                        .finish();
+                }
+            }
+        }
+
+        let request_path = build_request_path(path, route);
+        let upstream_host = build_upstream_host(&host, route);
 
-    // Convert the reqwest response to an Actix response
-    reqwest_response_to_actix(reqwest_response, data.proxy.as_ref(), &route).await
+        if websocket::is_upgrade_request(&req) {
+            debug!("Proxying WebSocket upgrade request");
+            let (upstream, response) = make_request(
+                req.clone(),
+                request_path.as_ref(),
+                RequestBody::Memory(Vec::new()),
+                route,
+                &upstream_host,
+                data,
+                clients.for_route(route, data.proxy.as_ref()),
+            ).await;
+            access_log.upstream = Some(upstream.to_string());
+            access_log.upstream_status = response.as_ref().ok().map(|x| x.status());
+
+            return match response {
+                Ok(response) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                    match websocket::tunnel(response, payload).await {
+                        Ok(x) => x,
+                        Err(e) => reqwest_response_to_actix(Err(e), data.proxy.as_ref(), route).await,
+                    }
+                },
+                response => reqwest_response_to_actix(response, data.proxy.as_ref(), route).await,
+            };
+        }
+
+        let max_request_body_size = data.proxy.as_ref().and_then(|x| x.max_request_body_size);
+        let content_length = req.headers().get(CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<u64>().ok());
+        if let Some(content_length) = content_length {
+            if check_body_size(content_length, max_request_body_size).is_err() {
+                debug!("Request body of {content_length} bytes is too large");
+                return HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+                    .insert_header(("Server", get_server_header(data.proxy.as_ref())))
+                    .finish();
+            }
+        }
+
+        // The body must be buffered if it may have to be sent to multiple upstream servers
+        let replayable = route.upstream.as_slice().len() > 1;
+        let body = match extract_body(payload, data.proxy.as_ref(), replayable).await {
+            Ok(x) => x,
+            Err(e) if e.is::<BodyTooLarge>() => {
+                debug!("{e}");
+                return HttpResponse::build(StatusCode::PAYLOAD_TOO_LARGE)
+                    .insert_header(("Server", get_server_header(data.proxy.as_ref())))
+                    .finish();
+            },
+            Err(e) => {
+                warn!("Failed to extract request body: {e}");
+                return HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE);
+            }
+        };
+
+        let started = Instant::now();
+        let response = if route.single_flight.unwrap_or(false) && is_idempotent(req.method()) {
+            let key = single_flight_key(&req, &host, route);
+
+            // The request outlives this handler if the client goes away while others wait for it,
+            // so it owns everything it needs
+            let upstream_request = {
+                let snapshot = snapshot.clone();
+                let route = route.clone();
+                let req = req.clone();
+                let request_path = request_path.to_string();
+                let upstream_host = upstream_host.to_string();
+                async move {
+                    logging::with_route_level(route.log_level_filter(), async {
+                        let data = &snapshot.config;
+                        let client = snapshot.clients.for_route(&route, data.proxy.as_ref());
+                        let max_size = data.proxy.as_ref().and_then(|x| x.max_response_body_size);
+                        let (upstream, response) = make_request(req, &request_path, body, &route, &upstream_host, data, client).await;
+                        buffer_response(upstream, response, max_size).await
+                    }).await
+                }
+            };
+
+            let response = single_flight.run(key, upstream_request).await;
+            access_log.upstream = match &response {
+                Ok(x) => Some(x.upstream.clone()),
+                Err(e) => e.upstream.clone(),
+            };
+            access_log.upstream_status = response.as_ref().ok().map(|x| x.status);
+
+            buffered_response_to_actix(response, data.proxy.as_ref(), route)
+        } else {
+            let (upstream, response) = make_request(
+                req.clone(),
+                request_path.as_ref(),
+                body,
+                route,
+                &upstream_host,
+                data,
+                clients.for_route(route, data.proxy.as_ref()),
+            ).await;
+            access_log.upstream = Some(upstream.to_string());
+            access_log.upstream_status = response.as_ref().ok().map(|x| x.status());
+
+            // Convert the reqwest response to an Actix response
+            reqwest_response_to_actix(response, data.proxy.as_ref(), route).await
+        };
+
+        let upstream = access_log.upstream.as_deref().unwrap_or("none");
+        log_if_slow(started.elapsed(), &req, route, upstream, data.proxy.as_ref());
+        response
+    }).await
+}
+
+/// Log a warning if the upstream request took longer than the configured threshold
+fn log_if_slow(elapsed: Duration, req: &HttpRequest, route: &Route, upstream: &str, proxy_config: Option<&ProxyConfig>) {
+    static SLOW_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+    let threshold = match route.slow_request_threshold_ms.or(proxy_config.and_then(|x| x.slow_request_threshold_ms)) {
+        Some(x) => Duration::from_millis(x),
+        None => return,
+    };
+
+    if elapsed > threshold {
+        let slow_requests = SLOW_REQUESTS.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!(
+            method = %req.method(),
+            path = req.path(),
+            route = route.path_prefix.as_deref().unwrap_or(""),
+            route_host = route.host.as_deref().unwrap_or(""),
+            upstream,
+            duration_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            slow_requests,
+            "Slow upstream request"
+        );
+    }
+}
+
+/// Whether requests with this method may be coalesced
+fn is_idempotent(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+/// Build the key identifying identical requests for single-flight mode.
+/// Consists of the method, host, path, query and the headers configured on the [Route]
+fn single_flight_key(req: &HttpRequest, host: &str, route: &Route) -> String {
+    let mut key = format!("{} {host}{}?{}", req.method(), req.path(), req.query_string());
+    for name in route.single_flight_headers.iter().flatten() {
+        for value in req.headers().get_all(name.as_str()) {
+            key.push('\n');
+            key.push_str(name);
+            key.push_str(": ");
+            key.push_str(&String::from_utf8_lossy(value.as_bytes()));
+        }
+    }
+
+    key
+}
+
+/// How the route for a request was chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RouteMatch {
+    HostAndPath,
+    WildcardHostAndPath,
+    Host,
+    WildcardHost,
+    Path,
+    Default,
+}
+
+impl RouteMatch {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::HostAndPath => "host_and_path",
+            Self::WildcardHostAndPath => "wildcard_host_and_path",
+            Self::Host => "host",
+            Self::WildcardHost => "wildcard_host",
+            Self::Path => "path",
+            Self::Default => "default",
+        }
+    }
 }
 
-fn choose_route<'a>(host: &str, path: &str, routes: Vec<&'a Route>) -> Option<&'a Route> {
+fn choose_route<'a>(host: &str, path: &str, routes: Vec<&'a Route>) -> Option<(&'a Route, RouteMatch)> {
     let mut route_has_host_and_path = Vec::new();
+    let mut route_has_wildcard_host_and_path = Vec::new();
     let mut route_has_host = Vec::new();
+    let mut route_has_wildcard_host = Vec::new();
     let mut route_has_path = Vec::new();
     let mut default_routes = Vec::new();
 
     for route in routes {
         if let (Some(route_host), Some(route_path)) = (&route.host, &route.path_prefix) {
             debug!("route and path present: {route_host} {route_path}");
-            debug!("route: {}", route_host.eq(host));
+            debug!("route: {:?}", match_host(route_host, host));
             debug!("path: {}", path.starts_with(route_path));
 
-            if route_host.eq(host) && path.starts_with(route_path) {
-                route_has_host_and_path.push(route);
+            if !path.starts_with(route_path) {
+                continue;
+            }
+
+            match match_host(route_host, host) {
+                Some(HostMatch::Exact) => route_has_host_and_path.push(route),
+                Some(HostMatch::Wildcard) => route_has_wildcard_host_and_path.push(route),
+                None => {}
             }
         } else if let Some(route_host) = &route.host {
-            if route_host.eq(host) {
-                route_has_host.push(route);
+            match match_host(route_host, host) {
+                Some(HostMatch::Exact) => route_has_host.push(route),
+                Some(HostMatch::Wildcard) => route_has_wildcard_host.push(route),
+                None => {}
             }
         } else  if let Some(route_path) = &route.path_prefix {
             if path.starts_with(route_path) {
@@ -86,27 +396,52 @@ fn choose_route<'a>(host: &str, path: &str, routes: Vec<&'a Route>) -> Option<&'
         }
     }
 
-    if let Some(route) = route_has_host_and_path.first() {
-        trace!("Host and path route chosen");
-        return Some(route);
-    }
+    let chosen = longest_path_prefix(&route_has_host_and_path).map(|x| (x, RouteMatch::HostAndPath))
+        .or_else(|| route_has_host.first().map(|x| (*x, RouteMatch::Host)))
+        .or_else(|| longest_path_prefix(&route_has_wildcard_host_and_path).map(|x| (x, RouteMatch::WildcardHostAndPath)))
+        .or_else(|| route_has_wildcard_host.first().map(|x| (*x, RouteMatch::WildcardHost)))
+        .or_else(|| longest_path_prefix(&route_has_path).map(|x| (x, RouteMatch::Path)))
+        .or_else(|| default_routes.first().map(|x| (*x, RouteMatch::Default)));
 
-    else if let Some(route) = route_has_host.first() {
-        trace!("Host route chosen");
-        return Some(route);
+    if let Some((_, route_match)) = &chosen {
+        trace!("{} route chosen", route_match.as_str());
     }
 
-    if let Some(route) = route_has_path.first() {
-        trace!("Path route chosen");
-        return Some(route);
+    chosen
+}
+
+/// How the host of a route matched the request host
+#[derive(Debug, PartialEq, Eq)]
+enum HostMatch {
+    /// The route host is equal to the request host
+    Exact,
+    /// The route host is a wildcard, e.g. `*.example.com`, matching the request host
+    Wildcard,
+}
+
+/// Match the host of a route against the request host.
+/// A leading `*.` in the route host matches any subdomain,
+/// e.g. `*.example.com` matches `a.example.com` and `a.b.example.com`, but not `example.com`
+fn match_host(route_host: &str, host: &str) -> Option<HostMatch> {
+    if route_host.eq(host) {
+        return Some(HostMatch::Exact);
     }
 
-    if let Some(route) = default_routes.first() {
-        trace!("Default route chosen");
-        return Some(route);
+    let suffix = route_host.strip_prefix('*')?;
+    if suffix.starts_with('.') && host.ends_with(suffix) && host.len() > suffix.len() {
+        Some(HostMatch::Wildcard)
+    } else {
+        None
     }
+}
 
-    None
+/// Pick the route with the longest path prefix.
+/// If multiple routes have an equally long path prefix, the first one is picked.
+fn longest_path_prefix<'a>(routes: &[&'a Route]) -> Option<&'a Route> {
+    routes.iter()
+        .rev()
+        .max_by_key(|x| x.path_prefix.as_ref().map(|x| x.len()).unwrap_or(0))
+        .copied()
 }
 
 fn get_request_host(req: &HttpRequest) -> Option<String> {
@@ -115,60 +450,405 @@ fn get_request_host(req: &HttpRequest) -> Option<String> {
         None => req.uri().host()
     };
 
-    // Split off potential port
-    let host = host.map(|x| {
-        let split = x.split(":").collect::<Vec<_>>();
-        split.first().map(|x| x.to_string())
-    }).flatten();
+    let host = host.map(|x| strip_port(x).to_string());
 
     trace!("Got Host {host:?}");
     host
 }
 
+/// Split off a potential port from a host, e.g. `example.com:8080` or `[::1]:8080`
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        };
+    }
+
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Check whether the request host matches the server name the client
+/// indicated during the TLS handshake. Always true if strict SNI checking
+/// is disabled, or if the client did not send a server name.
+fn sni_matches_host(req: &HttpRequest, host: &str, tls_config: Option<&TlsConfig>) -> bool {
+    let strict_sni = tls_config
+        .and_then(|x| x.strict_sni)
+        .unwrap_or(false);
+    if !strict_sni {
+        return true;
+    }
+
+    match req.conn_data::<SniHostname>() {
+        Some(sni) => sni.0.eq_ignore_ascii_case(host),
+        None => true,
+    }
+}
+
 /// Build the path that should be used in the upstream request
 /// according to the settings specified in the [Route]
 fn build_request_path<'a>(orig_path: &'a str, route: &Route) -> Cow<'a, str> {
     if let (Some(path_prefix), Some(strip_prefix_path)) = (&route.path_prefix, route.strip_path_prefix) {
         if strip_prefix_path {
-            return Cow::Owned(orig_path.replace(path_prefix.as_str(), ""));
+            if let Some(stripped) = orig_path.strip_prefix(path_prefix.as_str()) {
+                // The upstream request target must be a valid absolute path
+                return if stripped.starts_with('/') {
+                    Cow::Borrowed(stripped)
+                } else {
+                    Cow::Owned(format!("/{stripped}"))
+                };
+            }
         }
     }
 
     Cow::Borrowed(orig_path)
 }
 
-/// Extract the request body
-async fn extract_body(mut body: web::Payload) -> Result<Vec<u8>> {
+/// Build the host that should be forwarded to the upstream server
+/// according to the settings specified in the [Route]
+fn build_upstream_host<'a>(orig_host: &'a str, route: &Route) -> Cow<'a, str> {
+    if route.lowercase_host.unwrap_or(false) {
+        return Cow::Owned(orig_host.to_lowercase());
+    }
+
+    Cow::Borrowed(orig_host)
+}
+
+/// The body of the client's request
+enum RequestBody {
+    /// The body is streamed to the upstream server as it is received
+    Streaming(web::Payload),
+    /// The body is held in memory
+    Memory(Vec<u8>),
+    /// The body was spooled to a temporary file
+    Spooled(SpooledBody),
+}
+
+/// A handle to a spooled request body.
+/// Every handle opens the file separately, so it has its own read position
+struct SpooledBody {
+    file: fs::File,
+    spool_file: Arc<SpoolFile>,
+}
+
+/// A temporary file holding a request body, which is removed
+/// once the last [SpooledBody] referring to it is dropped
+struct SpoolFile {
+    path: PathBuf,
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove spooled request body {}: {e}", self.path.display());
+        }
+    }
+}
+
+impl RequestBody {
+    /// Convert the body into a body for the upstream request.
+    /// The size of a streaming body is limited to `max_size` bytes
+    fn into_reqwest_body(self, max_size: Option<u64>) -> reqwest::Body {
+        match self {
+            Self::Streaming(x) => stream_payload(x, max_size),
+            Self::Memory(x) => x.into(),
+            // The file may be removed once opened, the open handle keeps it readable
+            Self::Spooled(x) => x.file.into(),
+        }
+    }
+
+    /// Create a copy of the body to send to another upstream server.
+    /// Streaming bodies can only be sent once.
+    /// A copy of a spooled body reopens the file, so an earlier upstream
+    /// request still reading the body doesn't affect the copy
+    async fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Streaming(_) => Err(io::Error::other("A streaming body can't be sent more than once")),
+            Self::Memory(x) => Ok(Self::Memory(x.clone())),
+            Self::Spooled(x) => Ok(Self::Spooled(SpooledBody {
+                file: fs::File::open(&x.spool_file.path).await?,
+                spool_file: x.spool_file.clone(),
+            })),
+        }
+    }
+}
+
+type BoxError = Box<dyn std::error::Error>;
+
+/// The request body is larger than the configured maximum
+#[derive(Debug, Error)]
+#[error("The request body exceeds the maximum size of {0} bytes")]
+struct BodyTooLarge(u64);
+
+/// Check the number of bytes received so far against the maximum body size
+fn check_body_size(received: u64, max_size: Option<u64>) -> Result<(), BodyTooLarge> {
+    match max_size {
+        Some(max_size) if received > max_size => Err(BodyTooLarge(max_size)),
+        _ => Ok(()),
+    }
+}
+
+/// The spool threshold for bodies which must be replayable, if none is configured
+const DEFAULT_REPLAYABLE_SPOOL_THRESHOLD: usize = 1024 * 1024;
+
+/// Extract the request body.
+/// The body is streamed, unless spooling is configured or the body must be `replayable`.
+/// In that case the body is buffered, and if it exceeds the spool threshold, spooled to disk
+async fn extract_body(mut body: web::Payload, proxy_config: Option<&ProxyConfig>, replayable: bool) -> Result<RequestBody> {
+    let spool_threshold = match proxy_config.and_then(|x| x.body_spool_threshold) {
+        Some(x) => x,
+        None if replayable => DEFAULT_REPLAYABLE_SPOOL_THRESHOLD,
+        None => return Ok(RequestBody::Streaming(body)),
+    };
+    let max_size = proxy_config.and_then(|x| x.max_request_body_size);
+
     let mut buf = Vec::new();
     while let Some(b) = body.next().await {
         let b = b?;
         buf.extend_from_slice(&b);
+        check_body_size(buf.len() as u64, max_size)?;
+
+        if buf.len() > spool_threshold {
+            let spool_dir = proxy_config
+                .and_then(|x| x.body_spool_dir.clone())
+                .unwrap_or_else(std::env::temp_dir);
+            return spool_body(buf, body, &spool_dir, max_size).await;
+        }
+    }
+
+    Ok(RequestBody::Memory(buf))
+}
+
+/// Write the already received part of the body and the remainder
+/// of the body to a temporary file in `spool_dir`
+async fn spool_body(buf: Vec<u8>, mut body: web::Payload, spool_dir: &Path, max_size: Option<u64>) -> Result<RequestBody> {
+    static SPOOL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let path = spool_dir.join(format!(
+        "simpleproxy-{}-{}",
+        std::process::id(),
+        SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    trace!("Spooling request body to {}", path.display());
+
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .await?;
+
+    // Removes the file when the body is dropped, also on errors.
+    // The file is kept until then, as it is reopened for every upstream request
+    let spool_file = Arc::new(SpoolFile {
+        path,
+    });
+
+    let mut received = buf.len() as u64;
+    file.write_all(&buf).await?;
+    while let Some(b) = body.next().await {
+        let b = b?;
+        received += b.len() as u64;
+        check_body_size(received, max_size)?;
+        file.write_all(&b).await?;
     }
 
-    Ok(buf)
+    file.flush().await?;
+    file.seek(SeekFrom::Start(0)).await?;
+
+    Ok(RequestBody::Spooled(SpooledBody {
+        file,
+        spool_file,
+    }))
+}
+
+/// Stream the client's request body to the upstream server.
+/// The payload can't be sent between threads, so it is read on the
+/// current worker and the chunks are passed to Reqwest over a channel
+fn stream_payload(mut payload: web::Payload, max_size: Option<u64>) -> reqwest::Body {
+    let (tx, rx) = mpsc::channel::<io::Result<Bytes>>(16);
+
+    actix_web::rt::spawn(async move {
+        let mut received = 0_u64;
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk
+                .map_err(|e| io::Error::other(e.to_string()))
+                .and_then(|chunk| {
+                    received += chunk.len() as u64;
+                    check_body_size(received, max_size)
+                        .map(|_| chunk)
+                        .map_err(io::Error::other)
+                });
+
+            let failed = chunk.is_err();
+            if let Err(e) = &chunk {
+                warn!("Failed to stream request body: {e}");
+            }
+
+            // The upstream request was dropped
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    reqwest::Body::wrap_stream(futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
 }
 
 fn get_server_header(proxy_config: Option<&ProxyConfig>) -> String {
     proxy_config
-        .map(|x| x.error_server_header.clone())
-        .flatten()
-        .unwrap_or(String::default())
+        .and_then(|x| x.error_server_header.clone())
+        .unwrap_or_default()
 }
 
 /// Turn a Reqwest response into an Actix response
 async fn reqwest_response_to_actix(response: reqwest::Result<Response>, proxy_config: Option<&ProxyConfig>, route: &Route) -> HttpResponse {
     let response = match response {
         Ok(x) => x,
-        Err(e) => return HttpResponse::build(StatusCode::BAD_GATEWAY)
+        Err(e) => return upstream_error_response(e.is_timeout(), e.to_string(), proxy_config),
+    };
+
+    trace!("Remote server addr: {:?}", response.remote_addr().map(|x| x.to_string()));
+    trace!("Got response status {} from server", response.status().as_u16());
+
+    let mut builder = match build_response(response.status(), response.headers(), route, proxy_config) {
+        Some(x) => x,
+        None => return HttpResponse::build(StatusCode::BAD_GATEWAY)
+            .insert_header(("Server", get_server_header(proxy_config)))
+            .finish(),
+    };
+
+    // Taken from the header, as the body of e.g. a response to a HEAD request is always empty
+    let content_length = response.headers().get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok());
+
+    let max_size = proxy_config.and_then(|x| x.max_response_body_size);
+    if let Some(content_length) = content_length {
+        if check_body_size(content_length, max_size).is_err() {
+            warn!("Upstream response body of {content_length} bytes exceeds the maximum size");
+            return HttpResponse::build(StatusCode::BAD_GATEWAY)
                 .insert_header(("Server", get_server_header(proxy_config)))
-                .body(e.to_string()),
+                .finish();
+        }
+    }
+
+    // Stream the upstream response body to the client
+    let mut received = 0_u64;
+    let stream = response.bytes_stream()
+        .map(move |chunk| {
+            let chunk = chunk.map_err(|e| {
+                warn!("Failed to stream response body from upstream: {e}");
+                BoxError::from(e)
+            })?;
+
+            received += chunk.len() as u64;
+            if let Err(e) = check_body_size(received, max_size) {
+                warn!("Upstream response body exceeds the maximum size of {} bytes", e.0);
+                return Err(BoxError::from(e));
+            }
+
+            Ok(chunk)
+        });
+
+    match content_length {
+        Some(len) => builder.body(SizedStream::new(len, stream)),
+        None => builder.body(BodyStream::new(stream)),
+    }
+}
+
+/// Buffer a Reqwest response, so it can be shared between coalesced requests.
+/// The body is limited to `max_size` bytes
+async fn buffer_response(upstream: &str, response: reqwest::Result<Response>, max_size: Option<u64>) -> SharedResult {
+    let shared_error = |message: String, timed_out: bool| SharedError {
+        message,
+        timed_out,
+        upstream: Some(upstream.to_string()),
     };
 
+    let response = response.map_err(|e| shared_error(e.to_string(), e.is_timeout()))?;
+
     trace!("Remote server addr: {:?}", response.remote_addr().map(|x| x.to_string()));
     trace!("Got response status {} from server", response.status().as_u16());
 
-    let mut builder = HttpResponse::build(response.status());
-    for (k, v) in response.headers() {
+    let content_length = response.headers().get(CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse::<u64>().ok());
+    if let Some(content_length) = content_length {
+        if let Err(e) = check_body_size(content_length, max_size) {
+            warn!("Upstream response body of {content_length} bytes exceeds the maximum size");
+            return Err(shared_error(format!("The upstream response body exceeds the maximum size of {} bytes", e.0), false));
+        }
+    }
+
+    let status = response.status();
+    let headers = response.headers().clone();
+
+    let mut body = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| shared_error(format!("Failed to extract response bytes from Reqwest response: {e}"), e.is_timeout()))?;
+
+        body.extend_from_slice(&chunk);
+        if let Err(e) = check_body_size(body.len() as u64, max_size) {
+            warn!("Upstream response body exceeds the maximum size of {} bytes", e.0);
+            return Err(shared_error(format!("The upstream response body exceeds the maximum size of {} bytes", e.0), false));
+        }
+    }
+
+    Ok(Arc::new(BufferedResponse {
+        upstream: upstream.to_string(),
+        status,
+        headers,
+        body: Bytes::from(body),
+    }))
+}
+
+/// Turn a buffered response into an Actix response
+fn buffered_response_to_actix(response: SharedResult, proxy_config: Option<&ProxyConfig>, route: &Route) -> HttpResponse {
+    match response {
+        Ok(response) => match build_response(response.status, &response.headers, route, proxy_config) {
+            Some(mut builder) => builder.body(response.body.clone()),
+            None => HttpResponse::build(StatusCode::BAD_GATEWAY)
+                .insert_header(("Server", get_server_header(proxy_config)))
+                .finish(),
+        },
+        Err(e) => upstream_error_response(e.timed_out, e.message, proxy_config),
+    }
+}
+
+/// The response to the client when the upstream request failed.
+/// Timeouts result in `504 Gateway Timeout`, all other failures in `502 Bad Gateway`
+fn upstream_error_response(timed_out: bool, message: String, proxy_config: Option<&ProxyConfig>) -> HttpResponse {
+    let status = if timed_out {
+        debug!("Upstream request timed out");
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::BAD_GATEWAY
+    };
+
+    HttpResponse::build(status)
+        .insert_header(("Server", get_server_header(proxy_config)))
+        .body(message)
+}
+
+/// Create an Actix response builder with the status and headers of the upstream response,
+/// including the response headers configured on the [Route].
+/// Returns `None` if the upstream headers exceed the configured size limit
+/// and the policy is to reject such responses
+fn build_response(status: StatusCode, headers: &HeaderMap, route: &Route, proxy_config: Option<&ProxyConfig>) -> Option<HttpResponseBuilder> {
+    let headers = limit_response_headers(headers, proxy_config)?;
+
+    let hop_by_hop = HopByHop::new(headers.get_all(CONNECTION));
+    let mut builder = HttpResponse::build(status);
+    for (k, v) in headers.iter() {
+        if hop_by_hop.contains(k) {
+            continue;
+        }
+
         builder.insert_header((k, v));
     }
 
@@ -178,29 +858,171 @@ async fn reqwest_response_to_actix(response: reqwest::Result<Response>, proxy_co
         }
     }
 
-    let body = match response.bytes().await {
-        Ok(x) => x,
-        Err(e) => {
-            warn!("Failed to extract response bytes from Reqwest response: {e}");
-            return HttpResponse::new(StatusCode::SERVICE_UNAVAILABLE);
-        }
+    Some(builder)
+}
+
+/// The size of a header as it appears on the wire, i.e. `{name}: {value}\r\n`
+fn header_size(name: &HeaderName, value: &HeaderValue) -> usize {
+    name.as_str().len() + value.len() + 4
+}
+
+/// Enforce the configured maximum total size of the upstream response headers.
+/// Returns the headers to forward, or `None` if the response should be rejected
+fn limit_response_headers<'a>(headers: &'a HeaderMap, proxy_config: Option<&ProxyConfig>) -> Option<Cow<'a, HeaderMap>> {
+    let max_size = match proxy_config.and_then(|x| x.max_response_header_size) {
+        Some(x) => x,
+        None => return Some(Cow::Borrowed(headers)),
     };
 
-    builder.body(body)
+    let total_size: usize = headers.iter()
+        .map(|(k, v)| header_size(k, v))
+        .sum();
+    if total_size <= max_size {
+        return Some(Cow::Borrowed(headers));
+    }
+
+    let policy = proxy_config
+        .and_then(|x| x.oversized_response_headers)
+        .unwrap_or_default();
+
+    match policy {
+        OversizedHeadersPolicy::Reject => {
+            warn!("Upstream response headers are {total_size} bytes, exceeding the maximum of {max_size} bytes. Rejecting response");
+            None
+        },
+        OversizedHeadersPolicy::Truncate => {
+            // Drop the largest headers first, until the limit is satisfied
+            let mut sorted = headers.iter().collect::<Vec<_>>();
+            sorted.sort_by_key(|(k, v)| std::cmp::Reverse(header_size(k, v)));
+
+            let mut size = total_size;
+            let mut dropped = Vec::new();
+            for (k, v) in sorted {
+                if size <= max_size {
+                    break;
+                }
+
+                size -= header_size(k, v);
+                dropped.push((k, v));
+            }
+
+            warn!(
+                "Upstream response headers are {total_size} bytes, exceeding the maximum of {max_size} bytes. Dropping headers: {}",
+                dropped.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>().join(", ")
+            );
+
+            let mut limited = HeaderMap::with_capacity(headers.len());
+            for (k, v) in headers {
+                if !dropped.iter().any(|(dk, dv)| *dk == k && *dv == v) {
+                    limited.append(k, v.clone());
+                }
+            }
+
+            Some(Cow::Owned(limited))
+        }
+    }
+}
+
+/// Whether the request body should be dropped for this method,
+/// according to the settings specified in the [Route] or the [ProxyConfig]
+fn should_drop_body(method: &Method, route: &Route, proxy_config: Option<&ProxyConfig>) -> bool {
+    route.drop_body_methods.as_ref()
+        .or(proxy_config.and_then(|x| x.drop_body_methods.as_ref()))
+        .map(|methods| methods.iter().any(|x| x.eq_ignore_ascii_case(method.as_str())))
+        .unwrap_or(false)
+}
+
+/// Determine the port for the `X-Forwarded-Port` header.
+/// This is the port of the listener the request was received on.
+/// If forwarding headers are trusted, the port is taken from the incoming
+/// `X-Forwarded-Port` header, or if only `X-Forwarded-Proto` is present,
+/// the default port for that protocol.
+fn forwarded_port(req: &HttpRequest, proxy_config: Option<&ProxyConfig>) -> String {
+    let trust_forwarded_port = proxy_config
+        .and_then(|x| x.trust_forwarded_port)
+        .unwrap_or(false);
+
+    if trust_forwarded_port {
+        let header = |name: &str| req.headers().get(name)
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty());
+
+        if let Some(port) = header("x-forwarded-port") {
+            return port.to_string();
+        }
+
+        match header("x-forwarded-proto") {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => return "443".into(),
+            Some(proto) if proto.eq_ignore_ascii_case("http") => return "80".into(),
+            _ => {}
+        }
+    }
+
+    req.app_config().local_addr().port().to_string()
 }
 
-/// Proxy the request to the provided upstream server.
-async fn make_request(
+/// Send the request to the upstream servers of the route, in the order of its `upstream_policy`.
+/// If an upstream server can't be reached or responds with a server error,
+/// the request is sent to the next upstream server.
+/// The result of the last upstream server is returned as is,
+/// together with the upstream server it was received from
+async fn make_request<'a>(
     req: HttpRequest,
     path: &str,
-    body: Vec<u8>,
+    body: RequestBody,
+    route: &'a Route,
+    original_host: &str,
+    config: &Config,
+    client: &Client,
+) -> (&'a str, reqwest::Result<Response>) {
+    let mut upstreams = route.upstreams_in_order().into_iter().peekable();
+    let mut body = Some(body);
+
+    loop {
+        let upstream = upstreams.next().expect("Routes have at least one upstream");
+        let current_body = body.take().expect("The body is kept for the next upstream");
+
+        let attempt_body = if upstreams.peek().is_some() {
+            match current_body.try_clone().await {
+                Ok(x) => {
+                    body = Some(current_body);
+                    x
+                },
+                Err(e) => {
+                    warn!("Failed to copy request body, not trying other upstreams: {e}");
+                    current_body
+                }
+            }
+        } else {
+            current_body
+        };
+
+        let response = build_request(req.clone(), upstream, path, attempt_body, route, original_host, config, client).send().await;
+        if body.is_none() {
+            return (upstream, response);
+        }
+
+        match &response {
+            Ok(x) if x.status().is_server_error() => warn!("Upstream {upstream} responded with {}, trying the next upstream", x.status()),
+            Ok(_) => return (upstream, response),
+            Err(e) => warn!("Request to upstream {upstream} failed, trying the next upstream: {e}"),
+        }
+    }
+}
+
+/// Build the request to a single upstream server
+#[allow(clippy::too_many_arguments)]
+fn build_request(
+    req: HttpRequest,
     upstream: &str,
+    path: &str,
+    body: RequestBody,
+    route: &Route,
     original_host: &str,
-) -> reqwest::Result<Response> {
-    let client = Client::builder()
-        .redirect(Policy::none())
-        .build()
-        .unwrap();
+    config: &Config,
+    client: &Client,
+) -> RequestBuilder {
 
     let request_url = if req.query_string().is_empty() {
        format!("{upstream}{path}")
@@ -212,8 +1034,12 @@ async fn make_request(
     let mut req_builder = client.request(
         req.method().clone(),
         &request_url,
-    )
-        .version(Version::HTTP_11);
+    );
+
+    let timeout_ms = route.timeout_ms.or(config.proxy.as_ref().and_then(|x| x.timeout_ms));
+    if let Some(timeout_ms) = timeout_ms {
+        req_builder = req_builder.timeout(Duration::from_millis(timeout_ms));
+    }
 
     // Some applications don't like multiple headers,
     // so we'll combine it.
@@ -236,31 +1062,227 @@ async fn make_request(
         })
         .collect::<HashMap<_, _>>();
 
+    let drop_body = should_drop_body(req.method(), route, config.proxy.as_ref());
+    if drop_body {
+        debug!("Dropping request body for {} request", req.method());
+    }
+
+    let strip_accept_encoding = route.strip_accept_encoding.unwrap_or(false);
+    let overwrite_accept_encoding = strip_accept_encoding || route.accept_encoding.is_some();
+
+    // The upgrade headers must reach the upstream for it to accept a WebSocket upgrade
+    let upgrade = websocket::is_upgrade_request(&req);
+    let hop_by_hop = HopByHop::new(req.headers().get_all(CONNECTION));
+
     for (name, value) in processed_headers {
-	if name.as_str().to_lowercase().eq("host") {
+	if name.as_str().to_lowercase().eq("host") || name == "x-forwarded-port" {
+            continue;
+        }
+
+        if name == ACCEPT_ENCODING && overwrite_accept_encoding {
+            continue;
+        }
+
+        if hop_by_hop.contains(name) && !(upgrade && (name == CONNECTION || name == UPGRADE)) {
+            continue;
+        }
+
+        // The credentials are meant for the proxy
+        if name == AUTHORIZATION && route.basic_auth.is_some() {
+            continue;
+        }
+
+        // The framing headers no longer apply when the body is dropped
+        if drop_body && name == CONTENT_LENGTH {
             continue;
         }
 	
 	req_builder = req_builder.header(name, &value);
     }
 
+    if let (Some(accept_encoding), false) = (&route.accept_encoding, strip_accept_encoding) {
+        req_builder = req_builder.header(ACCEPT_ENCODING, accept_encoding);
+    }
+
     req_builder = req_builder.header("Host", original_host);
 
-    let conninfo = req.connection_info();
+    let (real_ip, scheme) = {
+        let conninfo = req.connection_info();
+        (conninfo.realip_remote_addr().unwrap_or("").to_string(), conninfo.scheme().to_string())
+    };
+
     let x_forwarded_for = req.headers().get("x-forwarded-for")
-        .map(|x| x.to_str().map(|x| Some(x)).unwrap_or(None))
-        .flatten()
+        .and_then(|x| x.to_str().ok())
         .map(|x| if !x.is_empty() {
-            format!("{x} {}", conninfo.realip_remote_addr().unwrap_or(""))
+            format!("{x} {real_ip}")
         } else { x.to_string() })
-        .unwrap_or(conninfo.realip_remote_addr().unwrap_or("").to_string());
+        .unwrap_or_else(|| real_ip.clone());
 
-    req_builder = req_builder
-        .header("X-Real-IP", conninfo.realip_remote_addr().unwrap_or(""))
+    req_builder
+        .header("X-Real-IP", &real_ip)
         .header("X-Forwarded-For", &x_forwarded_for)
-        .header("X-Forwarded-Proto", conninfo.scheme())
+        .header("X-Forwarded-Proto", &scheme)
         .header("X-Forwarded-Host", original_host)
-        .body(body);
+        .header("X-Forwarded-Port", forwarded_port(&req, config.proxy.as_ref()))
+        .body(if drop_body {
+            reqwest::Body::from(Vec::new())
+        } else {
+            body.into_reqwest_body(config.proxy.as_ref().and_then(|x| x.max_request_body_size))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use super::*;
+    use crate::config::Upstreams;
+
+    #[test]
+    fn upstream_host() {
+        let cases = [
+            (None, "Foo.Example.com"),
+            (Some(false), "Foo.Example.com"),
+            (Some(true), "foo.example.com"),
+        ];
+
+        let config = Config::default();
+        let client = Client::new();
+        for (lowercase_host, expected) in cases {
+            let route = Route {
+                lowercase_host,
+                ..Route::default()
+            };
+
+            let req = TestRequest::default()
+                .insert_header(("Host", "Foo.Example.com"))
+                .to_http_request();
+            let upstream_host = build_upstream_host("Foo.Example.com", &route);
+            let request = build_request(req, "http://127.0.0.1", "/", RequestBody::Memory(Vec::new()), &route, &upstream_host, &config, &client)
+                .build()
+                .unwrap();
+
+            assert_eq!(request.headers().get("Host").unwrap(), expected, "lowercase_host = {lowercase_host:?}");
+            assert_eq!(request.headers().get("X-Forwarded-Host").unwrap(), expected, "lowercase_host = {lowercase_host:?}");
+        }
+    }
+
+    fn path_route(path_prefix: &str, upstream: &str) -> Route {
+        Route {
+            host: None,
+            path_prefix: Some(path_prefix.to_string()),
+            upstream: Upstreams::Single(upstream.to_string()),
+            ..Route::default()
+        }
+    }
+
+    #[test]
+    fn longest_path_prefix_chosen() {
+        let api = path_route("/api", "http://api");
+        let api_v2 = path_route("/api/v2", "http://api-v2");
+
+        let route = choose_route("foo", "/api/v2/users", vec![&api, &api_v2]).unwrap().0;
+        assert_eq!(route.upstream.to_string(), "http://api-v2");
+
+        let route = choose_route("foo", "/api/v1/users", vec![&api, &api_v2]).unwrap().0;
+        assert_eq!(route.upstream.to_string(), "http://api");
+    }
+
+    #[test]
+    fn longest_host_and_path_prefix_chosen() {
+        let host_route = |path_prefix, upstream| Route {
+            host: Some("foo.example.com".to_string()),
+            ..path_route(path_prefix, upstream)
+        };
+        let api = host_route("/api", "http://api");
+        let api_v2 = host_route("/api/v2", "http://api-v2");
+
+        let route = choose_route("foo.example.com", "/api/v2/users", vec![&api_v2, &api]).unwrap().0;
+        assert_eq!(route.upstream.to_string(), "http://api-v2");
+    }
+
+    fn host_route(host: &str, upstream: &str) -> Route {
+        Route {
+            host: Some(host.to_string()),
+            path_prefix: None,
+            upstream: Upstreams::Single(upstream.to_string()),
+            ..Route::default()
+        }
+    }
+
+    #[test]
+    fn wildcard_host_matches_subdomains() {
+        assert_eq!(match_host("*.example.com", "a.example.com"), Some(HostMatch::Wildcard));
+        assert_eq!(match_host("*.example.com", "a.b.example.com"), Some(HostMatch::Wildcard));
+        assert_eq!(match_host("*.example.com", "example.com"), None);
+        assert_eq!(match_host("*.example.com", "aexample.com"), None);
+        assert_eq!(match_host("example.com", "example.com"), Some(HostMatch::Exact));
+    }
+
+    #[test]
+    fn exact_host_preferred_over_wildcard() {
+        let wildcard = host_route("*.example.com", "http://wildcard");
+        let exact = host_route("a.example.com", "http://exact");
 
-    req_builder.send().await
+        let route = choose_route("a.example.com", "/", vec![&wildcard, &exact]).unwrap().0;
+        assert_eq!(route.upstream.to_string(), "http://exact");
+
+        let route = choose_route("b.example.com", "/", vec![&wildcard, &exact]).unwrap().0;
+        assert_eq!(route.upstream.to_string(), "http://wildcard");
+    }
+
+    #[test]
+    fn exact_host_preferred_over_wildcard_host_and_path() {
+        let wildcard = Route {
+            path_prefix: Some("/api".to_string()),
+            ..host_route("*.example.com", "http://wildcard")
+        };
+        let exact = host_route("a.example.com", "http://exact");
+
+        let (route, route_match) = choose_route("a.example.com", "/api", vec![&wildcard, &exact]).unwrap();
+        assert_eq!(route.upstream.to_string(), "http://exact");
+        assert_eq!(route_match, RouteMatch::Host);
+
+        let (route, route_match) = choose_route("b.example.com", "/api", vec![&wildcard, &exact]).unwrap();
+        assert_eq!(route.upstream.to_string(), "http://wildcard");
+        assert_eq!(route_match, RouteMatch::WildcardHostAndPath);
+    }
+
+    #[test]
+    fn port_stripped_from_host() {
+        assert_eq!(strip_port("example.com:8080"), "example.com");
+        assert_eq!(strip_port("example.com"), "example.com");
+        assert_eq!(strip_port("[::1]:8080"), "[::1]");
+    }
+
+    fn strip_route(path_prefix: &str) -> Route {
+        Route {
+            strip_path_prefix: Some(true),
+            ..path_route(path_prefix, "http://api")
+        }
+    }
+
+    #[test]
+    fn path_prefix_stripped_only_at_start() {
+        let route = strip_route("/api");
+        assert_eq!(build_request_path("/api/get/api-docs", &route), "/get/api-docs");
+        assert_eq!(build_request_path("/api/v1/api", &route), "/v1/api");
+    }
+
+    #[test]
+    fn path_prefix_stripped_exact_match() {
+        let route = strip_route("/api");
+        assert_eq!(build_request_path("/api", &route), "/");
+
+        let route = strip_route("/api/");
+        assert_eq!(build_request_path("/api/foo", &route), "/foo");
+    }
+
+    #[test]
+    fn path_prefix_not_stripped_when_disabled() {
+        let route = Route {
+            strip_path_prefix: Some(false),
+            ..path_route("/api", "http://api")
+        };
+        assert_eq!(build_request_path("/api/foo", &route), "/api/foo");
+    }
 }
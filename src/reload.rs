@@ -0,0 +1,87 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use actix_web::web;
+use thiserror::Error;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info};
+use crate::client::UpstreamClients;
+use crate::config::{Config, ConfigError};
+use crate::logging;
+
+#[derive(Debug, Error)]
+pub enum ReloadError {
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("Failed to create upstream HTTP client: {0}")]
+    Client(#[from] reqwest::Error),
+}
+
+/// The configuration in use, together with the upstream clients created from it
+#[derive(Debug)]
+pub struct Snapshot {
+    pub config: Config,
+    pub clients: UpstreamClients,
+}
+
+/// The configuration of the proxy, which can be reloaded while the server is running.
+/// Changes to the `net` and `tls` sections only take effect after a restart
+#[derive(Debug)]
+pub struct ReloadableConfig {
+    path: PathBuf,
+    current: RwLock<Arc<Snapshot>>,
+}
+
+impl ReloadableConfig {
+    pub fn new(path: PathBuf, config: Config, clients: UpstreamClients) -> Self {
+        Self {
+            path,
+            current: RwLock::new(Arc::new(Snapshot {
+                config,
+                clients,
+            })),
+        }
+    }
+
+    /// The configuration to handle a request with.
+    /// A request keeps using the same configuration, even if it is reloaded in the meantime
+    pub fn load(&self) -> Arc<Snapshot> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Read and validate the configuration file, and if it is valid, start using it.
+    /// If it is not, the current configuration stays in use
+    pub async fn reload(&self) -> Result<(), ReloadError> {
+        // Don't fall back to writing the default configuration, as is done on startup
+        if !self.path.exists() {
+            return Err(ConfigError::FileNotFound(self.path.clone()).into());
+        }
+
+        let config = Config::new(&self.path).await?;
+        let clients = UpstreamClients::new(&config)?;
+        logging::set_route_levels(config.routes.iter().filter_map(|x| x.log_level_filter()));
+
+        *self.current.write().unwrap() = Arc::new(Snapshot {
+            config,
+            clients,
+        });
+
+        Ok(())
+    }
+}
+
+/// Reload the configuration whenever the process receives `SIGHUP`
+pub fn reload_on_sighup(config: web::Data<ReloadableConfig>) -> std::io::Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration from {}", config.path.display());
+            match config.reload().await {
+                Ok(_) => info!("Reloaded configuration"),
+                Err(e) => error!("Failed to reload configuration, keeping the current configuration: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use tokio::sync::broadcast;
+use actix_web::web::Bytes;
+use tracing::trace;
+
+/// A fully buffered upstream response, which can be shared between
+/// all requests waiting on the same upstream request.
+#[derive(Debug)]
+pub struct BufferedResponse {
+    /// The upstream server the response was received from
+    pub upstream: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// The outcome of a coalesced upstream request.
+pub type SharedResult = Result<Arc<BufferedResponse>, SharedError>;
+
+/// Why a coalesced upstream request failed
+#[derive(Debug, Clone)]
+pub struct SharedError {
+    /// A description of why the upstream request failed
+    pub message: String,
+    /// Whether the upstream request timed out
+    pub timed_out: bool,
+    /// The upstream server the request was sent to, if it was sent
+    pub upstream: Option<String>,
+}
+
+impl From<String> for SharedError {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            timed_out: false,
+            upstream: None,
+        }
+    }
+}
+
+/// Coalesces identical concurrent upstream requests,
+/// such that only one of them is sent to the upstream server
+/// and its response is shared with all others.
+#[derive(Debug, Default)]
+pub struct SingleFlight {
+    in_flight: Arc<Mutex<HashMap<String, broadcast::Sender<SharedResult>>>>,
+}
+
+impl SingleFlight {
+    /// Run the upstream request `fut` for `key`.
+    /// If a request for `key` is already in flight, `fut` is never polled
+    /// and the result of the in-flight request is returned instead.
+    /// The upstream request runs in its own task, so it is completed for
+    /// the waiting requests even if the request which started it goes away
+    pub async fn run<F: Future<Output = SharedResult> + 'static>(&self, key: String, fut: F) -> SharedResult {
+        let mut rx = match self.join(key) {
+            Ok((leader, rx)) => {
+                actix_web::rt::spawn(async move {
+                    let result = fut.await;
+                    leader.complete(result);
+                });
+                rx
+            },
+            Err(rx) => {
+                trace!("Joining in-flight upstream request");
+                rx
+            }
+        };
+
+        match rx.recv().await {
+            Ok(x) => x,
+            Err(_) => Err(String::from("The coalesced upstream request was aborted").into()),
+        }
+    }
+
+    /// Become the leader for `key`, or if a request for `key`
+    /// is already in flight, subscribe to its result.
+    fn join(&self, key: String) -> Result<(Leader, broadcast::Receiver<SharedResult>), broadcast::Receiver<SharedResult>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some(tx) = in_flight.get(&key) {
+            return Err(tx.subscribe());
+        }
+
+        let (tx, rx) = broadcast::channel(1);
+        in_flight.insert(key.clone(), tx);
+        Ok((Leader {
+            in_flight: self.in_flight.clone(),
+            key,
+            completed: false,
+        }, rx))
+    }
+}
+
+/// Held by the task which performs the upstream request for a key.
+/// If it is dropped before completing, e.g. because the upstream request panicked,
+/// the key is released and all waiting requests fail.
+struct Leader {
+    in_flight: Arc<Mutex<HashMap<String, broadcast::Sender<SharedResult>>>>,
+    key: String,
+    completed: bool,
+}
+
+impl Leader {
+    fn complete(mut self, result: SharedResult) {
+        self.completed = true;
+        if let Some(tx) = self.in_flight.lock().unwrap().remove(&self.key) {
+            // An error only means nobody is waiting anymore
+            let _ = tx.send(result);
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.in_flight.lock().unwrap().remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use futures_util::future::join_all;
+    use tokio::sync::oneshot;
+    use super::*;
+
+    fn response(body: &'static str) -> SharedResult {
+        Ok(Arc::new(BufferedResponse {
+            upstream: "http://upstream".to_string(),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(body.as_bytes()),
+        }))
+    }
+
+    /// Run an upstream request which counts how often it is polled.
+    /// If `release` is given, it completes with the result sent to it
+    async fn counted(single_flight: &SingleFlight, polled: Arc<AtomicUsize>, release: Option<oneshot::Receiver<SharedResult>>) -> SharedResult {
+        single_flight.run("key".to_string(), async move {
+            polled.fetch_add(1, Ordering::SeqCst);
+            match release {
+                Some(release) => release.await.unwrap(),
+                None => response("body"),
+            }
+        }).await
+    }
+
+    fn is_in_flight(single_flight: &SingleFlight) -> bool {
+        single_flight.in_flight.lock().unwrap().contains_key("key")
+    }
+
+    #[actix_web::test]
+    async fn identical_requests_are_coalesced() {
+        let single_flight = SingleFlight::default();
+        let polled = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = oneshot::channel();
+
+        let mut requests = vec![Box::pin(counted(&single_flight, polled.clone(), Some(rx)))];
+        for _ in 0..3 {
+            requests.push(Box::pin(counted(&single_flight, polled.clone(), None)));
+        }
+
+        let (results, _) = tokio::join!(join_all(requests), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.send(response("leader")).unwrap();
+        });
+
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(result.unwrap().body, "leader");
+        }
+    }
+
+    #[actix_web::test]
+    async fn error_reaches_every_waiter() {
+        let single_flight = SingleFlight::default();
+        let polled = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = oneshot::channel();
+
+        let requests = vec![
+            Box::pin(counted(&single_flight, polled.clone(), Some(rx))),
+            Box::pin(counted(&single_flight, polled.clone(), None)),
+        ];
+
+        let (results, _) = tokio::join!(join_all(requests), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.send(Err(String::from("upstream failed").into())).unwrap();
+        });
+
+        for result in results {
+            assert_eq!(result.unwrap_err().message, "upstream failed");
+        }
+    }
+
+    #[actix_web::test]
+    async fn completed_request_frees_key() {
+        let single_flight = SingleFlight::default();
+        let polled = Arc::new(AtomicUsize::new(0));
+
+        assert!(counted(&single_flight, polled.clone(), None).await.is_ok());
+        assert!(!is_in_flight(&single_flight));
+        assert!(counted(&single_flight, polled.clone(), None).await.is_ok());
+        assert_eq!(polled.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_web::test]
+    async fn dropped_leader_does_not_abort_waiters() {
+        let single_flight = SingleFlight::default();
+        let polled = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = oneshot::channel();
+
+        // The client of the leading request goes away
+        let leader = tokio::time::timeout(Duration::from_millis(10), counted(&single_flight, polled.clone(), Some(rx))).await;
+        assert!(leader.is_err());
+        assert!(is_in_flight(&single_flight));
+
+        let (waiter, _) = tokio::join!(counted(&single_flight, polled.clone(), None), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            tx.send(response("leader")).unwrap();
+        });
+
+        assert_eq!(waiter.unwrap().body, "leader");
+        assert_eq!(polled.load(Ordering::SeqCst), 1);
+        assert!(!is_in_flight(&single_flight));
+    }
+
+    #[actix_web::test]
+    async fn aborted_request_frees_key() {
+        let single_flight = SingleFlight::default();
+        let polled = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = oneshot::channel::<SharedResult>();
+
+        let (result, _) = tokio::join!(counted(&single_flight, polled.clone(), Some(rx)), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            // The upstream request fails without producing a result
+            drop(tx);
+        });
+
+        assert_eq!(result.unwrap_err().message, "The coalesced upstream request was aborted");
+        assert!(!is_in_flight(&single_flight));
+    }
+}
@@ -1,10 +1,15 @@
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
 use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, pkcs8_private_keys};
-use std::io::Cursor;
+use rustls_pemfile::{certs, ec_private_keys, pkcs8_private_keys, rsa_private_keys};
+use std::any::Any;
+use std::io::{BufRead, Cursor};
 use std::path::{Path, PathBuf};
 use thiserror::Error;
 use tokio::fs;
 use tokio::io::AsyncReadExt;
+use tracing::debug;
 
 #[derive(Debug, Error)]
 pub enum TlsError {
@@ -12,12 +17,27 @@ pub enum TlsError {
     Io(#[from] std::io::Error),
     #[error("File not found: {0}")]
     FileNotFound(PathBuf),
-    #[error("The provided private key file contains no PKCS8 private key")]
+    #[error("The provided private key file contains no PKCS8, RSA (PKCS1) or EC (SEC1) private key")]
     NoPrivateKey,
     #[error("The provided certificate file containers no certificate")]
     NoCertificates,
 }
 
+/// The server name the client indicated (SNI) during the TLS handshake
+#[derive(Debug, Clone)]
+pub struct SniHostname(pub String);
+
+/// Connection callback for the HTTP server.
+/// Stores the [SniHostname] of TLS connections in the connection data.
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    if let Some(tls_stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = tls_stream.get_ref();
+        if let Some(sni) = session.sni_hostname() {
+            ext.insert(SniHostname(sni.to_string()));
+        }
+    }
+}
+
 /// Create a TLS configuration for the provided certificate and associated private key
 pub async fn configure_tls<P: AsRef<Path>, P1: AsRef<Path>>(
     cert_path: P,
@@ -26,31 +46,32 @@ pub async fn configure_tls<P: AsRef<Path>, P1: AsRef<Path>>(
     let certificate_pem_bytes = read_file_to_vec(cert_path).await?;
     let privkey_pem_bytes = read_file_to_vec(privkey_path).await?;
 
+    build_server_config(certificate_pem_bytes, privkey_pem_bytes)
+}
+
+/// Create a TLS configuration from a single PEM file
+/// containing both the certificate and the private key
+pub async fn configure_tls_combined<P: AsRef<Path>>(pem_path: P) -> Result<ServerConfig, TlsError> {
+    let pem_bytes = read_file_to_vec(pem_path).await?;
+    build_server_config(pem_bytes.clone(), pem_bytes)
+}
+
+/// Create a TLS configuration from the PEM encoded certificates and private key
+fn build_server_config(certificate_pem_bytes: Vec<u8>, privkey_pem_bytes: Vec<u8>) -> Result<ServerConfig, TlsError> {
     // Extract the certificates
     let mut cursor = Cursor::new(certificate_pem_bytes);
     let raw_certificates = certs(&mut cursor)?;
     let certificates = raw_certificates
         .into_iter()
-        .map(|x| Certificate(x))
+        .map(Certificate)
         .collect::<Vec<_>>();
 
     if certificates.is_empty() {
         return Err(TlsError::NoCertificates);
     }
 
-    // Extract the private keys
-    let mut cursor = Cursor::new(privkey_pem_bytes);
-    let raw_privkeys = pkcs8_private_keys(&mut cursor)?;
-    let mut privkeys = raw_privkeys
-        .into_iter()
-        .map(|x| PrivateKey(x))
-        .collect::<Vec<_>>();
-
-    if privkeys.is_empty() {
-        return Err(TlsError::NoPrivateKey);
-    }
-
-    let privkey = privkeys.remove(0);
+    // Extract the private key
+    let privkey = extract_private_key(&privkey_pem_bytes)?;
 
     let config = ServerConfig::builder()
         .with_safe_default_cipher_suites()
@@ -64,8 +85,31 @@ pub async fn configure_tls<P: AsRef<Path>, P1: AsRef<Path>>(
     Ok(config)
 }
 
+/// Parser for private keys of one format from PEM encoded bytes
+type PrivateKeyParser = fn(&mut dyn BufRead) -> std::io::Result<Vec<Vec<u8>>>;
+
+/// Extract the first private key from PEM encoded bytes.
+/// PKCS8, RSA (PKCS1) and EC (SEC1) private keys are supported, tried in that order
+fn extract_private_key(privkey_pem_bytes: &[u8]) -> Result<PrivateKey, TlsError> {
+    let formats: [(&str, PrivateKeyParser); 3] = [
+        ("PKCS8", pkcs8_private_keys),
+        ("RSA (PKCS1)", rsa_private_keys),
+        ("EC (SEC1)", ec_private_keys),
+    ];
+
+    for (format, parse) in formats {
+        let mut cursor = Cursor::new(privkey_pem_bytes);
+        if let Some(privkey) = parse(&mut cursor)?.into_iter().next() {
+            debug!("Detected {format} private key");
+            return Ok(PrivateKey(privkey));
+        }
+    }
+
+    Err(TlsError::NoPrivateKey)
+}
+
 /// Read a file at the provided path into a Vec of bytes.
-async fn read_file_to_vec<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, TlsError> {
+pub async fn read_file_to_vec<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, TlsError> {
     let path = path.as_ref();
     if !path.exists() {
         return Err(TlsError::FileNotFound(path.to_path_buf()));
@@ -0,0 +1,95 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::body::BodyStream;
+use actix_web::http::header::{CONNECTION, UPGRADE};
+use actix_web::web::Bytes;
+use futures_util::StreamExt;
+use reqwest::Response;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, trace};
+use crate::hop_by_hop::HopByHop;
+
+/// The size of the buffer used to read from the upstream connection
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Whether the request asks to upgrade the connection to a WebSocket,
+/// i.e. it has a `Connection: Upgrade` and `Upgrade: websocket` header
+pub fn is_upgrade_request(req: &HttpRequest) -> bool {
+    let connection_upgrade = req.headers().get_all(CONNECTION)
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .any(|x| x.trim().eq_ignore_ascii_case("upgrade"));
+
+    let upgrade_websocket = req.headers().get_all(UPGRADE)
+        .filter_map(|x| x.to_str().ok())
+        .any(|x| x.trim().eq_ignore_ascii_case("websocket"));
+
+    connection_upgrade && upgrade_websocket
+}
+
+/// Complete the upgrade of the connection to the upstream server,
+/// and relay the WebSocket traffic between the client and the upstream server
+/// until either side closes the connection.
+pub async fn tunnel(response: Response, mut payload: web::Payload) -> reqwest::Result<HttpResponse> {
+    // The upgrade headers are set by Actix for the upgraded client connection
+    let hop_by_hop = HopByHop::new(response.headers().get_all(CONNECTION));
+    let mut builder = HttpResponse::build(response.status());
+    for (k, v) in response.headers() {
+        if hop_by_hop.contains(k) {
+            continue;
+        }
+
+        builder.insert_header((k, v));
+    }
+
+    let upgrade = response.headers().get(UPGRADE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or("websocket")
+        .to_string();
+
+    let upgraded = response.upgrade().await?;
+
+    trace!("Upgraded upstream connection to {upgrade}");
+    let (upstream_read, mut upstream_write) = tokio::io::split(upgraded);
+
+    // Client to upstream
+    actix_web::rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = match chunk {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("Failed to read WebSocket data from client: {e}");
+                    break;
+                }
+            };
+
+            if let Err(e) = upstream_write.write_all(&chunk).await {
+                debug!("Failed to write WebSocket data to upstream: {e}");
+                break;
+            }
+        }
+
+        trace!("Client closed the WebSocket connection");
+        let _ = upstream_write.shutdown().await;
+    });
+
+    // Upstream to client
+    let stream = futures_util::stream::unfold(Some(upstream_read), |upstream_read| async move {
+        let mut upstream_read = upstream_read?;
+        let mut buf = vec![0; READ_BUFFER_SIZE];
+        match upstream_read.read(&mut buf).await {
+            Ok(0) => {
+                trace!("Upstream closed the WebSocket connection");
+                None
+            },
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(upstream_read)))
+            },
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+
+    Ok(builder
+        .upgrade(upgrade)
+        .body(BodyStream::new(stream)))
+}